@@ -1,19 +1,437 @@
-use crate::models::vc::ResponseVC;
-use anyhow::Result;
-
-const WEATHER_BASE_URL: &str =
-    "https://weather.visualcrossing.com/VisualCrossingWebServices/rest/services/timeline";
-
-pub async fn fetch_weather_api<S: AsRef<str>>(city: S) -> Result<ResponseVC> {
-    let api_key = std::env::var("WEATHER_API_KEY").expect("WEATHER_API_KEY must be set");
-    let url = format!(
-        "{}/{}?unitGroup=metric&key={}&contentType=json",
-        WEATHER_BASE_URL,
-        city.as_ref(),
-        api_key
-    );
-    let response = reqwest::get(url).await?;
-    let json = serde_json::from_str::<ResponseVC>(response.text().await?.as_str())?;
-
-    Ok(json)
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use reqwest::StatusCode;
+
+use crate::config::{self, Config, ProviderKind};
+use crate::error::WeatherError;
+use crate::failover::FailoverChain;
+use crate::models::domain::WeatherReport;
+use crate::models::{Place, WeatherApiType, WeatherLang, WeatherQuery, om, owm, vc::ResponseVC};
+
+#[async_trait]
+pub trait WeatherProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn supports(&self, _api_type: &WeatherApiType) -> bool {
+        true
+    }
+    // whether the report's cost counts against our paid quota
+    fn billed(&self) -> bool {
+        true
+    }
+    async fn fetch(&self, query: &WeatherQuery) -> Result<WeatherReport>;
+}
+
+pub fn provider_from_config(config: &Config) -> Result<Arc<dyn WeatherProvider>> {
+    let mut providers: Vec<Arc<dyn WeatherProvider>> = Vec::new();
+    for kind in &config.providers {
+        let provider: Arc<dyn WeatherProvider> = match kind {
+            ProviderKind::VisualCrossing => Arc::new(VisualCrossing::new(
+                api_key(&config.visualcrossing_key_var)?,
+                config.endpoints.visualcrossing.clone(),
+            )),
+            ProviderKind::OpenWeatherMap => Arc::new(OpenWeatherMap::new(
+                api_key(&config.openweathermap_key_var)?,
+                config.endpoints.openweathermap.clone(),
+                config.endpoints.openweathermap_forecast.clone(),
+            )),
+            ProviderKind::OpenMeteo => Arc::new(OpenMeteo::new(
+                config.endpoints.openmeteo_geocoding.clone(),
+                config.endpoints.openmeteo_forecast.clone(),
+            )),
+        };
+        providers.push(provider);
+    }
+    Ok(Arc::new(FailoverChain::new(
+        providers,
+        config.upstream_timeout,
+        config.breaker_threshold,
+        config.breaker_cooldown,
+    )))
+}
+
+fn api_key(var: &str) -> Result<String> {
+    config::api_key(var).ok_or_else(|| anyhow!("{} must be set", var))
+}
+
+// visual crossing answers 400 for an unknown location, the others only for a
+// request they can't make sense of
+async fn get_json<T: serde::de::DeserializeOwned>(
+    provider: &str,
+    request: reqwest::RequestBuilder,
+    city: &str,
+    bad_request_is_not_found: bool,
+) -> Result<T> {
+    let response = request
+        .send()
+        .await
+        .map_err(|e| transport_error(provider, e))?;
+    match response.status() {
+        StatusCode::BAD_REQUEST if bad_request_is_not_found => {
+            return Err(WeatherError::LocationNotFound(city.to_string()).into());
+        }
+        StatusCode::BAD_REQUEST => {
+            return Err(
+                WeatherError::UpstreamSchema(format!("{} rejected the request", provider)).into(),
+            );
+        }
+        StatusCode::NOT_FOUND => {
+            return Err(WeatherError::LocationNotFound(city.to_string()).into());
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            return Err(WeatherError::UpstreamAuth(provider.to_string()).into());
+        }
+        StatusCode::TOO_MANY_REQUESTS => {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            return Err(WeatherError::UpstreamRateLimited(retry_after).into());
+        }
+        _ => {}
+    }
+    let response = response
+        .error_for_status()
+        .map_err(|e| transport_error(provider, e))?;
+    let text = response
+        .text()
+        .await
+        .map_err(|e| transport_error(provider, e))?;
+    serde_json::from_str::<T>(&text)
+        .map_err(|e| WeatherError::UpstreamSchema(format!("{}: {}", provider, e)).into())
+}
+
+// reqwest errors name the url, which carries our api key. clients get a fixed
+// description, the log gets the detail with the key masked
+fn transport_error(provider: &str, e: reqwest::Error) -> anyhow::Error {
+    let description = if e.is_timeout() {
+        "timed out".to_string()
+    } else if e.is_connect() {
+        "connection failed".to_string()
+    } else if let Some(status) = e.status() {
+        format!("answered {}", status)
+    } else {
+        "request failed".to_string()
+    };
+    match e.url() {
+        Some(url) => eprintln!(
+            "{} request to {} failed: {}",
+            provider,
+            redact(url),
+            e.without_url()
+        ),
+        None => eprintln!("{} request failed: {}", provider, e),
+    }
+    WeatherError::UpstreamUnavailable(format!("{} {}", provider, description)).into()
+}
+
+const SECRET_PARAMS: &[&str] = &["key", "appid"];
+
+fn redact(url: &reqwest::Url) -> reqwest::Url {
+    let mut redacted = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if SECRET_PARAMS.contains(&name.as_ref()) {
+                "***".to_string()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    redacted.query_pairs_mut().clear().extend_pairs(pairs);
+    redacted
+}
+
+pub struct VisualCrossing {
+    client: reqwest::Client,
+    api_key: String,
+    base_url: String,
+}
+
+impl VisualCrossing {
+    pub fn new(api_key: String, base_url: String) -> VisualCrossing {
+        VisualCrossing {
+            client: reqwest::Client::new(),
+            api_key,
+            base_url,
+        }
+    }
+}
+
+#[async_trait]
+impl WeatherProvider for VisualCrossing {
+    fn name(&self) -> &'static str {
+        "visualcrossing"
+    }
+    async fn fetch(&self, query: &WeatherQuery) -> Result<WeatherReport> {
+        // the timeline takes "lat,lon" in place of an address as is
+        let location = query.place.to_string();
+        let mut url = reqwest::Url::parse(&self.base_url)?;
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| anyhow!("invalid base url"))?;
+            segments.push(&location);
+            // without a date range the timeline returns the next 15 days
+            match (&query.api_type, query.dates) {
+                (WeatherApiType::Current, _) => {
+                    segments.push("today");
+                }
+                (WeatherApiType::Historical, Some((start, end))) => {
+                    segments.push(&start.to_string()).push(&end.to_string());
+                }
+                (WeatherApiType::Historical, None) => {
+                    return Err(anyhow!("historical query without dates"));
+                }
+                (WeatherApiType::Forecast, _) => {}
+            }
+        }
+        let include = match query.api_type {
+            WeatherApiType::Current => "current,days,alerts",
+            WeatherApiType::Forecast => "days,hours,alerts",
+            WeatherApiType::Historical => "days",
+        };
+        // always metric, other units are converted locally from the cached report
+        let request = self.client.get(url).query(&[
+            ("unitGroup", "metric"),
+            ("include", include),
+            ("lang", &query.lang.to_string()),
+            ("key", self.api_key.as_str()),
+            ("contentType", "json"),
+        ]);
+        let response: ResponseVC = get_json(self.name(), request, &location, true).await?;
+        Ok(response.into())
+    }
+}
+
+pub struct OpenWeatherMap {
+    client: reqwest::Client,
+    api_key: String,
+    weather_url: String,
+    forecast_url: String,
+}
+
+impl OpenWeatherMap {
+    pub fn new(api_key: String, weather_url: String, forecast_url: String) -> OpenWeatherMap {
+        OpenWeatherMap {
+            client: reqwest::Client::new(),
+            api_key,
+            weather_url,
+            forecast_url,
+        }
+    }
+}
+
+#[async_trait]
+impl WeatherProvider for OpenWeatherMap {
+    fn name(&self) -> &'static str {
+        "openweathermap"
+    }
+    // history is a paid subscription there
+    fn supports(&self, api_type: &WeatherApiType) -> bool {
+        *api_type != WeatherApiType::Historical
+    }
+    async fn fetch(&self, query: &WeatherQuery) -> Result<WeatherReport> {
+        let city = query.place.to_string();
+        let city = city.as_str();
+        let url = match query.api_type {
+            WeatherApiType::Current => &self.weather_url,
+            WeatherApiType::Forecast => &self.forecast_url,
+            WeatherApiType::Historical => {
+                return Err(anyhow!("openweathermap has no historical data"));
+            }
+        };
+        let mut params = match &query.place {
+            Place::City(name) => vec![("q", name.clone())],
+            Place::Coords {
+                latitude,
+                longitude,
+            } => vec![
+                ("lat", latitude.to_string()),
+                ("lon", longitude.to_string()),
+            ],
+        };
+        params.extend([
+            ("units", "metric".to_string()),
+            ("lang", query.lang.to_string()),
+            ("appid", self.api_key.clone()),
+        ]);
+        let request = self.client.get(url).query(&params);
+        match query.api_type {
+            WeatherApiType::Current => {
+                let response: owm::ResponseOWM =
+                    get_json(self.name(), request, city, false).await?;
+                Ok(response.into())
+            }
+            WeatherApiType::Forecast | WeatherApiType::Historical => {
+                let response: owm::ForecastOWM =
+                    get_json(self.name(), request, city, false).await?;
+                Ok(response.into())
+            }
+        }
+    }
+}
+
+pub struct OpenMeteo {
+    client: reqwest::Client,
+    geocoding_url: String,
+    forecast_url: String,
+}
+
+impl OpenMeteo {
+    pub fn new(geocoding_url: String, forecast_url: String) -> OpenMeteo {
+        OpenMeteo {
+            client: reqwest::Client::new(),
+            geocoding_url,
+            forecast_url,
+        }
+    }
+
+    async fn geocode(&self, city: &str, lang: WeatherLang) -> Result<om::PlaceOM> {
+        let request = self.client.get(&self.geocoding_url).query(&[
+            ("name", city),
+            ("count", "1"),
+            ("language", &lang.to_string()),
+            ("format", "json"),
+        ]);
+        let geocoding: om::GeocodingOM = get_json(self.name(), request, city, false).await?;
+        geocoding
+            .results
+            .into_iter()
+            .next()
+            .ok_or_else(|| WeatherError::LocationNotFound(city.to_string()).into())
+    }
+}
+
+#[async_trait]
+impl WeatherProvider for OpenMeteo {
+    fn name(&self) -> &'static str {
+        "openmeteo"
+    }
+    // the archive is a separate api with another schema, not wired up
+    fn supports(&self, api_type: &WeatherApiType) -> bool {
+        *api_type != WeatherApiType::Historical
+    }
+    fn billed(&self) -> bool {
+        false
+    }
+    async fn fetch(&self, query: &WeatherQuery) -> Result<WeatherReport> {
+        if !self.supports(&query.api_type) {
+            return Err(anyhow!("openmeteo has no historical data"));
+        }
+        let city = query.place.to_string();
+        let city = city.as_str();
+        let place = match &query.place {
+            Place::City(name) => self.geocode(name, query.lang).await?,
+            // coordinates need no geocoding, the name stays the coordinates
+            Place::Coords {
+                latitude,
+                longitude,
+            } => om::PlaceOM {
+                name: city.to_string(),
+                latitude: *latitude as f32,
+                longitude: *longitude as f32,
+                country: None,
+            },
+        };
+        let mut params = vec![
+            ("latitude", place.latitude.to_string()),
+            ("longitude", place.longitude.to_string()),
+            (
+                "daily",
+                "temperature_2m_max,temperature_2m_min,weather_code,precipitation_sum,\
+                 precipitation_probability_max,wind_speed_10m_max"
+                    .to_string(),
+            ),
+            ("timezone", "auto".to_string()),
+            ("timeformat", "unixtime".to_string()),
+        ];
+        match query.api_type {
+            WeatherApiType::Current => {
+                params.push((
+                    "current",
+                    "temperature_2m,relative_humidity_2m,pressure_msl,wind_speed_10m,weather_code"
+                        .to_string(),
+                ));
+                params.push(("forecast_days", "1".to_string()));
+            }
+            WeatherApiType::Forecast => {
+                params.push((
+                    "hourly",
+                    "temperature_2m,relative_humidity_2m,pressure_msl,wind_speed_10m,\
+                     precipitation,precipitation_probability,weather_code"
+                        .to_string(),
+                ));
+                params.push(("forecast_days", "16".to_string()));
+            }
+            WeatherApiType::Historical => {}
+        }
+        let request = self.client.get(&self.forecast_url).query(&params);
+        let response: om::ResponseOM = get_json(self.name(), request, city, false).await?;
+        // weather texts are translated locally, open-meteo only has codes
+        response
+            .into_report(place, query.lang)
+            .map_err(|e| WeatherError::UpstreamSchema(format!("{}: {}", self.name(), e)).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_masks_api_keys_only() {
+        let url = reqwest::Url::parse(
+            "https://example.com/timeline/moscow?unitGroup=metric&key=secret&appid=secret",
+        )
+        .unwrap();
+        let redacted = redact(&url).to_string();
+        assert!(!redacted.contains("secret"));
+        assert!(redacted.contains("unitGroup=metric"));
+        assert!(redacted.starts_with("https://example.com/timeline/moscow?"));
+    }
+
+    #[tokio::test]
+    async fn transport_errors_do_not_carry_the_url() {
+        // nothing listens on port 9, the connection is refused
+        let request = reqwest::Client::new().get("http://127.0.0.1:9/?key=secret");
+        let e = get_json::<serde_json::Value>("test", request, "moscow", false)
+            .await
+            .unwrap_err();
+        let message = WeatherError::from_upstream(e).to_string();
+        assert!(!message.contains("secret"), "{}", message);
+    }
+
+    // an upstream that answers 400 to everything
+    async fn bad_request_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = axum::Router::new().fallback(|| async { StatusCode::BAD_REQUEST });
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        format!("http://{}/", addr)
+    }
+
+    #[tokio::test]
+    async fn bad_request_is_not_found_only_where_the_provider_says_so() {
+        let url = bad_request_server().await;
+        let client = reqwest::Client::new();
+        let e = get_json::<serde_json::Value>("test", client.get(&url), "moscow", true)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            WeatherError::from_upstream(e),
+            WeatherError::LocationNotFound(_)
+        ));
+        let e = get_json::<serde_json::Value>("test", client.get(&url), "moscow", false)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            WeatherError::from_upstream(e),
+            WeatherError::UpstreamSchema(_)
+        ));
+    }
 }
@@ -0,0 +1,157 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lru::LruCache;
+
+use crate::models::WeatherApiType;
+
+const DAY: u64 = 24 * 60 * 60;
+
+// cache buckets are derived from the server clock, never from what the client sends
+#[derive(Debug)]
+pub struct TimeBuckets {
+    width: u64,
+    // utc offsets in seconds, learned from upstream responses. bounded like the
+    // response cache, a forgotten offset is learned again on the next fetch
+    offsets: Mutex<LruCache<String, i64>>,
+}
+
+impl TimeBuckets {
+    pub fn new(width: Duration, capacity: NonZeroUsize) -> TimeBuckets {
+        TimeBuckets {
+            width: width.as_secs().clamp(1, DAY),
+            offsets: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    fn width_for(&self, api_type: &WeatherApiType) -> u64 {
+        match api_type {
+            WeatherApiType::Current => self.width,
+            // a daily forecast only changes meaningfully from one local day to the next
+            WeatherApiType::Forecast | WeatherApiType::Historical => DAY,
+        }
+    }
+
+    // start of the bucket containing `now`, aligned to the location's local midnight
+    pub fn bucket_for(&self, location: &str, api_type: &WeatherApiType, now: u64) -> u64 {
+        let width = self.width_for(api_type) as i64;
+        let offset = self.offset(location);
+        let local = now as i64 + offset;
+        (local - local.rem_euclid(width) - offset).max(0) as u64
+    }
+
+    pub fn learn_offset(&self, location: &str, tzoffset_hours: f32) {
+        let offset = (tzoffset_hours * 3600.0).round() as i64;
+        if let Ok(mut offsets) = self.offsets.lock() {
+            offsets.put(location.to_string(), offset);
+        }
+    }
+
+    fn offset(&self, location: &str) -> i64 {
+        self.offsets
+            .lock()
+            .ok()
+            .and_then(|mut offsets| offsets.get(location).copied())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+    // 2024-01-01 00:00 utc
+    const NEW_YEAR: u64 = 1_704_067_200;
+
+    fn new_buckets() -> TimeBuckets {
+        TimeBuckets::new(Duration::from_secs(2 * HOUR), NonZeroUsize::new(2).unwrap())
+    }
+
+    fn buckets(offset_hours: f32) -> TimeBuckets {
+        let buckets = new_buckets();
+        buckets.learn_offset("here", offset_hours);
+        buckets
+    }
+
+    #[test]
+    fn unknown_locations_align_to_utc() {
+        let buckets = new_buckets();
+        let now = NEW_YEAR + 5 * HOUR;
+        assert_eq!(
+            buckets.bucket_for("elsewhere", &WeatherApiType::Forecast, now),
+            NEW_YEAR
+        );
+        assert_eq!(
+            buckets.bucket_for("elsewhere", &WeatherApiType::Current, now),
+            NEW_YEAR + 4 * HOUR
+        );
+    }
+
+    #[test]
+    fn positive_offsets_align_to_local_midnight() {
+        // 01:30 utc is 04:30 at +3
+        let buckets = buckets(3.0);
+        let now = NEW_YEAR + 90 * 60;
+        assert_eq!(
+            buckets.bucket_for("here", &WeatherApiType::Forecast, now),
+            NEW_YEAR - 3 * HOUR
+        );
+        assert_eq!(
+            buckets.bucket_for("here", &WeatherApiType::Current, now),
+            NEW_YEAR + HOUR
+        );
+    }
+
+    #[test]
+    fn negative_offsets_align_to_local_midnight() {
+        // 02:00 utc is still 21:00 on december 31st at -5
+        let buckets = buckets(-5.0);
+        let now = NEW_YEAR + 2 * HOUR;
+        assert_eq!(
+            buckets.bucket_for("here", &WeatherApiType::Forecast, now),
+            NEW_YEAR - 19 * HOUR
+        );
+        assert_eq!(
+            buckets.bucket_for("here", &WeatherApiType::Current, now),
+            NEW_YEAR + HOUR
+        );
+    }
+
+    #[test]
+    fn fractional_offsets_align_to_local_midnight() {
+        // 00:00 utc is 05:30 at +5:30 and 20:30 the day before at -3:30
+        let india = buckets(5.5);
+        assert_eq!(
+            india.bucket_for("here", &WeatherApiType::Forecast, NEW_YEAR),
+            NEW_YEAR - 5 * HOUR - 30 * 60
+        );
+        assert_eq!(
+            india.bucket_for("here", &WeatherApiType::Current, NEW_YEAR),
+            NEW_YEAR - HOUR - 30 * 60
+        );
+        let newfoundland = buckets(-3.5);
+        assert_eq!(
+            newfoundland.bucket_for("here", &WeatherApiType::Forecast, NEW_YEAR),
+            NEW_YEAR - 20 * HOUR - 30 * 60
+        );
+    }
+
+    #[test]
+    fn least_recently_used_offsets_are_forgotten() {
+        let buckets = new_buckets();
+        buckets.learn_offset("first", 3.0);
+        buckets.learn_offset("second", 3.0);
+        buckets.learn_offset("third", 3.0);
+        // back on utc until a fetch teaches it again
+        assert_eq!(
+            buckets.bucket_for("first", &WeatherApiType::Forecast, NEW_YEAR),
+            NEW_YEAR
+        );
+        assert_eq!(
+            buckets.bucket_for("third", &WeatherApiType::Forecast, NEW_YEAR),
+            NEW_YEAR - 3 * HOUR
+        );
+    }
+}
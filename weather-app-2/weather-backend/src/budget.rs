@@ -0,0 +1,158 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use chrono::{NaiveDate, Utc};
+use redis::AsyncCommands;
+use redis::aio::MultiplexedConnection;
+
+use crate::config::Config;
+use crate::models::api::BudgetStatus;
+use crate::storage::Storage;
+
+// what the upstreams billed us today. once spend nears the daily budget the
+// backend stops calling them and serves what it has cached. days are utc, the
+// same as visual crossing's quota. with a redis the total is shared by every
+// replica, otherwise it is kept per instance in the history store so a
+// restart doesn't forget it
+pub struct Budget {
+    daily: Option<f64>,
+    degrade_at: f64,
+    storage: Storage,
+    redis: Option<MultiplexedConnection>,
+    // the last total seen, what is served when redis can't be reached
+    spent: Mutex<(NaiveDate, f64)>,
+}
+
+impl Budget {
+    const PREFIX: &str = "weather:budget";
+    // a day's key outlives the day a little, so late readers still see it
+    const KEY_TTL_SECS: i64 = 2 * 24 * 60 * 60;
+
+    pub async fn load(config: &Config, storage: Storage) -> Result<Budget> {
+        let redis = match config.uses_redis() {
+            true => Some(
+                redis::Client::open(config.redis_url.as_str())?
+                    .get_multiplexed_tokio_connection()
+                    .await?,
+            ),
+            false => None,
+        };
+        let today = today();
+        let spent = match redis {
+            Some(_) => 0.0,
+            None => storage.spend(today).await?,
+        };
+        let budget = Budget {
+            daily: config.daily_budget,
+            degrade_at: config.budget_degrade_at,
+            storage,
+            redis,
+            spent: Mutex::new((today, spent)),
+        };
+        budget.spent().await;
+        Ok(budget)
+    }
+
+    fn day_key(day: NaiveDate) -> String {
+        format!("{}:{}", Self::PREFIX, day)
+    }
+
+    async fn spent(&self) -> (NaiveDate, f64) {
+        let Some(con) = &self.redis else {
+            return self.add_local(0.0);
+        };
+        let today = today();
+        let mut con = con.clone();
+        match con.get::<_, Option<f64>>(Self::day_key(today)).await {
+            Ok(spent) => self.set_local(today, spent.unwrap_or(0.0)),
+            Err(e) => {
+                eprintln!("reading the shared upstream spend failed: {}", e);
+                self.add_local(0.0)
+            }
+        }
+    }
+
+    // starts over at utc midnight
+    fn add_local(&self, cost: f64) -> (NaiveDate, f64) {
+        let today = today();
+        let mut spent = self.spent.lock().unwrap();
+        if spent.0 != today {
+            *spent = (today, 0.0);
+        }
+        spent.1 += cost;
+        *spent
+    }
+
+    fn set_local(&self, day: NaiveDate, total: f64) -> (NaiveDate, f64) {
+        let mut spent = self.spent.lock().unwrap();
+        *spent = (day, total);
+        *spent
+    }
+
+    fn over(&self, spent: f64) -> bool {
+        self.daily
+            .is_some_and(|daily| spent >= daily * self.degrade_at)
+    }
+
+    pub async fn degraded(&self) -> bool {
+        let (_, spent) = self.spent().await;
+        self.over(spent)
+    }
+
+    pub async fn charge(&self, cost: f32) {
+        let cost = cost as f64;
+        if cost <= 0.0 {
+            return;
+        }
+        let before = self.spent.lock().unwrap().1;
+        let (_, total) = match &self.redis {
+            Some(con) => self.charge_shared(con.clone(), cost).await,
+            None => self.charge_local(cost).await,
+        };
+        if !self.over(before) && self.over(total) {
+            eprintln!("upstream budget nearly spent, serving from cache only until tomorrow");
+        }
+    }
+
+    async fn charge_shared(&self, mut con: MultiplexedConnection, cost: f64) -> (NaiveDate, f64) {
+        let today = today();
+        let key = Self::day_key(today);
+        let total: redis::RedisResult<(f64, i64)> = redis::pipe()
+            .atomic()
+            .incr(&key, cost)
+            .expire(&key, Self::KEY_TTL_SECS)
+            .query_async(&mut con)
+            .await;
+        match total {
+            Ok((total, _)) => self.set_local(today, total),
+            Err(e) => {
+                eprintln!("recording the shared upstream spend failed: {}", e);
+                self.add_local(cost)
+            }
+        }
+    }
+
+    async fn charge_local(&self, cost: f64) -> (NaiveDate, f64) {
+        let (day, total) = self.add_local(cost);
+        if let Err(e) = self.storage.add_spend(day, cost).await {
+            eprintln!("recording upstream spend failed: {}", e);
+        }
+        (day, total)
+    }
+
+    pub async fn status(&self) -> BudgetStatus {
+        let (date, spent) = self.spent().await;
+        BudgetStatus {
+            date: date.to_string(),
+            spent,
+            budget: self.daily,
+            remaining: self.daily.map(|daily| (daily - spent).max(0.0)),
+            degrade_at: self.daily.map(|daily| daily * self.degrade_at),
+            degraded: self.over(spent),
+        }
+    }
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
@@ -1,120 +1,810 @@
-use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
-use axum::Json;
-use redis::Commands;
+use async_trait::async_trait;
+use lru::LruCache;
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::time::MissedTickBehavior;
 
-use crate::models::{CacheKey, api};
+use crate::config::{CacheBackend, CacheTtl, Config};
+use crate::metrics;
+use crate::models::{CacheKey, domain};
 
-const HOUR: u64 = 60 * 60;
+#[async_trait]
+pub trait Cache: Send + Sync {
+    // the backend label on metrics
+    fn name(&self) -> &'static str;
+    async fn set(&self, key: CacheKey, entry: CacheEntry);
+    async fn get(&self, key: &CacheKey) -> Option<CacheEntry>;
+    // the entry of the same series whose bucket is nearest to the requested one
+    async fn get_aprx(&self, key_aprx: &CacheKey) -> Option<CacheEntry>;
+    #[allow(dead_code)]
+    async fn del(&self, key: &CacheKey);
+    // may scan a shared store, so it is counted in the background, not per scrape
+    async fn len(&self) -> usize;
+    async fn stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+    async fn lookup(&self, key: &CacheKey) -> Freshness {
+        let (entry, approximate) = match self.get(key).await {
+            Some(entry) => (Some(entry), false),
+            None if key.api_type.approximable() => (self.get_aprx(key).await, true),
+            None => (None, false),
+        };
+        let freshness = Freshness::classify(entry, now_ts());
+        metrics::record_lookup(self.name(), &freshness, approximate);
+        freshness
+    }
+}
 
-pub trait Cache {
-    fn should_refresh(&self, key: &CacheKey, ts: u64) -> bool;
-    fn set(&mut self, key: CacheKey, value: Json<api::PreparedTemp>);
-    fn get(&self, key: &CacheKey) -> Option<Json<api::PreparedTemp>>;
-    fn get_aprx(&self, key_aprx: &CacheKey) -> Option<Json<api::PreparedTemp>>;
-    fn del(&mut self, key: &CacheKey);
-    fn len(&self) -> usize;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub value: domain::WeatherReport,
+    pub stored_at: u64,
+    pub expires_at: u64,
+    pub stale_until: u64,
 }
 
-#[derive(Debug)]
-pub struct CacheService<C: Cache> {
-    service: C,
+impl CacheEntry {
+    pub fn new(value: domain::WeatherReport, ttl: CacheTtl) -> CacheEntry {
+        let stored_at = now_ts();
+        let expires_at = stored_at.saturating_add(ttl.fresh_for.as_secs());
+        CacheEntry {
+            value,
+            stored_at,
+            expires_at,
+            stale_until: expires_at.saturating_add(ttl.stale_for.as_secs()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Freshness {
+    Fresh { entry: CacheEntry, age: u64 },
+    Stale { entry: CacheEntry, age: u64 },
+    Missing,
+}
+
+impl Freshness {
+    pub fn classify(entry: Option<CacheEntry>, now: u64) -> Freshness {
+        let Some(entry) = entry else {
+            return Freshness::Missing;
+        };
+        let age = now.saturating_sub(entry.stored_at);
+        // backends keep entries for the longest lived api type, shorter ones end here
+        if now >= entry.stale_until {
+            Freshness::Missing
+        } else if now < entry.expires_at {
+            Freshness::Fresh { entry, age }
+        } else {
+            Freshness::Stale { entry, age }
+        }
+    }
+}
+
+pub fn now_ts() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub evicted: u64,
+    pub expired: u64,
+}
+
+pub struct CacheService {
+    service: Box<dyn Cache>,
+}
+
+impl CacheService {
+    pub fn new<C: Cache + 'static>(service: C) -> CacheService {
+        CacheService {
+            service: Box::new(service),
+        }
+    }
+    pub async fn from_config(config: &Config) -> Result<CacheService> {
+        let service = match config.cache_backend {
+            CacheBackend::Memory => {
+                let runtime = RuntimeCache::new(config.cache_capacity, None, config.aprx_window);
+                runtime.spawn_sweeper(config.sweep_interval);
+                CacheService::new(runtime)
+            }
+            CacheBackend::Redis => CacheService::new(
+                RedisCache::new(&config.redis_url, config.cache_ttl(), config.aprx_window).await?,
+            ),
+            CacheBackend::Tiered => {
+                let l1 = RuntimeCache::new(
+                    config.cache_capacity,
+                    Some(config.l1_ttl),
+                    config.aprx_window,
+                );
+                l1.spawn_sweeper(config.sweep_interval);
+                let l2 = RedisCache::new(&config.redis_url, config.cache_ttl(), config.aprx_window)
+                    .await?;
+                CacheService::new(TieredCache::new(l1, Box::new(l2)))
+            }
+            CacheBackend::None => CacheService::new(NoCache),
+        };
+        Ok(service)
+    }
 }
 
-impl<C: Cache> CacheService<C> {
-    pub fn new(service: C) -> CacheService<C> {
-        CacheService { service }
+#[async_trait]
+impl Cache for CacheService {
+    fn name(&self) -> &'static str {
+        self.service.name()
+    }
+    async fn set(&self, key: CacheKey, entry: CacheEntry) {
+        self.service.set(key, entry).await;
+    }
+    async fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        self.service.get(key).await
+    }
+    async fn get_aprx(&self, key_aprx: &CacheKey) -> Option<CacheEntry> {
+        self.service.get_aprx(key_aprx).await
+    }
+    async fn del(&self, key: &CacheKey) {
+        self.service.del(key).await;
+    }
+    async fn len(&self) -> usize {
+        self.service.len().await
+    }
+    async fn stats(&self) -> CacheStats {
+        self.service.stats().await
     }
 }
 
-impl Cache for CacheService<RuntimeCache> {
-    fn should_refresh(&self, key: &CacheKey, ts: u64) -> bool {
-        self.service.should_refresh(key, ts)
+#[derive(Debug, Clone, Copy)]
+pub struct NoCache;
+
+#[async_trait]
+impl Cache for NoCache {
+    fn name(&self) -> &'static str {
+        "none"
     }
-    fn set(&mut self, key: CacheKey, value: Json<api::PreparedTemp>) {
-        self.service.set(key, value);
+    async fn set(&self, _key: CacheKey, _entry: CacheEntry) {}
+    async fn get(&self, _key: &CacheKey) -> Option<CacheEntry> {
+        None
     }
-    fn get(&self, key: &CacheKey) -> Option<Json<api::PreparedTemp>> {
-        self.service.get(key)
+    async fn get_aprx(&self, _key_aprx: &CacheKey) -> Option<CacheEntry> {
+        None
     }
-    fn get_aprx(&self, key_aprx: &CacheKey) -> Option<Json<api::PreparedTemp>> {
-        self.service.get_aprx(key_aprx)
+    async fn del(&self, _key: &CacheKey) {}
+    async fn len(&self) -> usize {
+        0
     }
-    fn del(&mut self, key: &CacheKey) {
-        self.service.del(key);
+}
+
+#[derive(Debug, Clone)]
+struct RuntimeEntry {
+    entry: CacheEntry,
+    inserted_at: Instant,
+}
+
+#[derive(Debug)]
+struct RuntimeInner {
+    responses: Mutex<LruCache<CacheKey, RuntimeEntry>>,
+    // entries also end at their own stale_until, this caps them further
+    ttl: Option<Duration>,
+    aprx_window: u64,
+    evicted: AtomicU64,
+    expired: AtomicU64,
+}
+
+impl RuntimeInner {
+    fn is_live(&self, entry: &RuntimeEntry) -> bool {
+        self.ttl.is_none_or(|ttl| entry.inserted_at.elapsed() < ttl)
+            && now_ts() < entry.entry.stale_until
     }
-    fn len(&self) -> usize {
-        self.service.len()
+
+    async fn sweep(&self) -> usize {
+        let mut responses = self.responses.lock().await;
+        let expired: Vec<CacheKey> = responses
+            .iter()
+            .filter(|(_, entry)| !self.is_live(entry))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            responses.pop(key);
+        }
+        self.expired
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        expired.len()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct RuntimeCache {
-    responses: HashMap<CacheKey, Json<api::PreparedTemp>>,
+    inner: Arc<RuntimeInner>,
 }
 
 impl RuntimeCache {
-    pub fn new() -> RuntimeCache {
+    pub fn new(
+        capacity: NonZeroUsize,
+        ttl: Option<Duration>,
+        aprx_window: Duration,
+    ) -> RuntimeCache {
         RuntimeCache {
-            responses: HashMap::new(),
+            inner: Arc::new(RuntimeInner {
+                responses: Mutex::new(LruCache::new(capacity)),
+                ttl,
+                aprx_window: aprx_window.as_secs(),
+                evicted: AtomicU64::new(0),
+                expired: AtomicU64::new(0),
+            }),
         }
     }
+
+    pub fn spawn_sweeper(&self, every: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                // the cache is gone, nothing left to sweep
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                let swept = inner.sweep().await;
+                if swept > 0 {
+                    println!("cache sweeper removed {} expired entries", swept);
+                }
+            }
+        });
+    }
 }
 
+#[async_trait]
 impl Cache for RuntimeCache {
-    fn should_refresh(&self, key: &CacheKey, ts: u64) -> bool {
-        self.responses.contains_key(key)
-            && ts
-                .checked_sub(key.timestamp)
-                .map(|sub| sub < HOUR * 2)
-                .unwrap_or(false)
+    fn name(&self) -> &'static str {
+        "memory"
     }
-    fn set(&mut self, key: CacheKey, value: Json<api::PreparedTemp>) {
-        self.responses.insert(key, value);
+    async fn set(&self, key: CacheKey, entry: CacheEntry) {
+        let entry = RuntimeEntry {
+            entry,
+            inserted_at: Instant::now(),
+        };
+        let mut responses = self.inner.responses.lock().await;
+        if let Some((evicted, _)) = responses.push(key.clone(), entry)
+            && evicted != key
+        {
+            self.inner.evicted.fetch_add(1, Ordering::Relaxed);
+        }
     }
-    fn get(&self, key: &CacheKey) -> Option<Json<api::PreparedTemp>> {
-        self.responses.get(key).cloned()
+    async fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        self.inner
+            .responses
+            .lock()
+            .await
+            .get(key)
+            .filter(|entry| self.inner.is_live(entry))
+            .map(|entry| entry.entry.clone())
     }
-    fn get_aprx(&self, key_aprx: &CacheKey) -> Option<Json<api::PreparedTemp>> {
-        if let Some(value) = self.get(key_aprx) {
-            return Some(value);
+    async fn get_aprx(&self, key_aprx: &CacheKey) -> Option<CacheEntry> {
+        let mut responses = self.inner.responses.lock().await;
+        if let Some(entry) = responses
+            .get(key_aprx)
+            .filter(|entry| self.inner.is_live(entry))
+        {
+            return Some(entry.entry.clone());
         }
-        self.responses
+        let found = responses
             .iter()
-            .find(|(k, _)| {
-                k.city == key_aprx.city
-                    && k.timestamp.checked_sub(key_aprx.timestamp).unwrap_or(0) < HOUR * 2
-            })
-            .map(|(_, v)| v.clone())
+            .filter(|(_, entry)| self.inner.is_live(entry))
+            .filter_map(|(k, _)| Some((k, k.bucket_distance(key_aprx)?)))
+            .filter(|(_, distance)| *distance <= self.inner.aprx_window)
+            .min_by_key(|(_, distance)| *distance)
+            .map(|(k, _)| k.clone())?;
+        responses.get(&found).map(|entry| entry.entry.clone())
+    }
+    async fn del(&self, key: &CacheKey) {
+        self.inner.responses.lock().await.pop(key);
     }
-    fn del(&mut self, key: &CacheKey) {
-        self.responses.remove(key);
+    async fn len(&self) -> usize {
+        self.inner.responses.lock().await.len()
     }
-    fn len(&self) -> usize {
-        self.responses.len()
+    async fn stats(&self) -> CacheStats {
+        CacheStats {
+            evicted: self.inner.evicted.load(Ordering::Relaxed),
+            expired: self.inner.expired.load(Ordering::Relaxed),
+        }
     }
 }
 
 pub struct RedisCache {
-    client: redis::Client,
+    con: MultiplexedConnection,
+    ttl: Duration,
+    aprx_window: u64,
 }
 
 impl RedisCache {
-    pub fn new() -> Result<RedisCache> {
-        let client = redis::Client::open("redis://127.0.0.1/")?;
-        Ok(RedisCache { client })
+    const PREFIX: &str = "weather";
+
+    pub async fn new(url: &str, ttl: Duration, aprx_window: Duration) -> Result<RedisCache> {
+        let client = redis::Client::open(url)?;
+        let con = client.get_multiplexed_tokio_connection().await?;
+        Ok(RedisCache {
+            con,
+            ttl,
+            aprx_window: aprx_window.as_secs(),
+        })
+    }
+
+    fn entry_key(key: &CacheKey) -> String {
+        format!("{}:entry:{}", Self::PREFIX, key)
+    }
+    fn index_key(key: &CacheKey) -> String {
+        format!(
+            "{}:index:{}-{}-{}-{}",
+            Self::PREFIX,
+            key.location,
+            key.api_type,
+            key.units,
+            key.lang
+        )
+    }
+
+    async fn try_get(&self, key: &CacheKey) -> Result<Option<CacheEntry>> {
+        let mut con = self.con.clone();
+        let raw: Option<String> = con.get(Self::entry_key(key)).await?;
+        match raw {
+            Some(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            None => Ok(None),
+        }
+    }
+    async fn try_set(&self, key: &CacheKey, entry: &CacheEntry) -> Result<()> {
+        let mut con = self.con.clone();
+        let raw = serde_json::to_string(entry)?;
+        let mut pipe = redis::pipe();
+        if entry.stale_until == u64::MAX {
+            pipe.set(Self::entry_key(key), raw).ignore();
+        } else {
+            pipe.set_ex(
+                Self::entry_key(key),
+                raw,
+                entry.stale_until.saturating_sub(now_ts()).max(1),
+            )
+            .ignore();
+        }
+        // only approximable series need the bucket index
+        if key.api_type.approximable() {
+            let index = Self::index_key(key);
+            pipe.zadd(&index, key.bucket_ts, key.bucket_ts)
+                .ignore()
+                .expire(&index, self.ttl.as_secs() as i64)
+                .ignore();
+        }
+        pipe.exec_async(&mut con).await?;
+        Ok(())
+    }
+    async fn try_get_aprx(&self, key_aprx: &CacheKey) -> Result<Option<CacheEntry>> {
+        if let Some(value) = self.try_get(key_aprx).await? {
+            return Ok(Some(value));
+        }
+
+        let mut con = self.con.clone();
+        let index = Self::index_key(key_aprx);
+        let mut buckets: Vec<u64> = con
+            .zrangebyscore(
+                &index,
+                key_aprx.bucket_ts.saturating_sub(self.aprx_window),
+                key_aprx.bucket_ts.saturating_add(self.aprx_window),
+            )
+            .await?;
+        buckets.sort_by_key(|bucket_ts| bucket_ts.abs_diff(key_aprx.bucket_ts));
+        for bucket_ts in buckets {
+            let candidate = CacheKey {
+                bucket_ts,
+                ..key_aprx.clone()
+            };
+            match self.try_get(&candidate).await? {
+                Some(value) => return Ok(Some(value)),
+                // the entry expired on its own, the index still remembers it
+                None => con.zrem(&index, bucket_ts).await?,
+            }
+        }
+        Ok(None)
+    }
+    async fn try_del(&self, key: &CacheKey) -> Result<()> {
+        let mut con = self.con.clone();
+        redis::pipe()
+            .del(Self::entry_key(key))
+            .ignore()
+            .zrem(Self::index_key(key), key.bucket_ts)
+            .ignore()
+            .exec_async(&mut con)
+            .await?;
+        Ok(())
+    }
+    // only our own namespace is counted, the redis may be shared
+    async fn try_len(&self) -> Result<usize> {
+        let mut con = self.con.clone();
+        let pattern = format!("{}:entry:*", Self::PREFIX);
+        let mut keys = con.scan_match::<_, String>(pattern).await?;
+        let mut len = 0;
+        while keys.next_item().await.is_some() {
+            len += 1;
+        }
+        Ok(len)
     }
 }
 
+#[async_trait]
 impl Cache for RedisCache {
-    fn get(&self, key: &CacheKey) -> Option<Json<api::PreparedTemp>> {
-        let mut con = self.client.get_connection().ok()?;
-        con.get(key).ok()?
-    }
-    fn set(&mut self, key: CacheKey, value: Json<api::PreparedTemp>) {}
-    fn get_aprx(&self, key_aprx: &CacheKey) -> Option<Json<api::PreparedTemp>> {}
-    fn should_refresh(&self, key: &CacheKey, ts: u64) -> bool {}
-    fn del(&mut self, key: &CacheKey) {}
-    fn len(&self) -> usize {}
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+    async fn set(&self, key: CacheKey, entry: CacheEntry) {
+        if let Err(e) = self.try_set(&key, &entry).await {
+            eprintln!("redis set failed: {}", e);
+        }
+    }
+    async fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        self.try_get(key).await.unwrap_or_else(|e| {
+            eprintln!("redis get failed: {}", e);
+            None
+        })
+    }
+    async fn get_aprx(&self, key_aprx: &CacheKey) -> Option<CacheEntry> {
+        self.try_get_aprx(key_aprx).await.unwrap_or_else(|e| {
+            eprintln!("redis get_aprx failed: {}", e);
+            None
+        })
+    }
+    async fn del(&self, key: &CacheKey) {
+        if let Err(e) = self.try_del(key).await {
+            eprintln!("redis del failed: {}", e);
+        }
+    }
+    async fn len(&self) -> usize {
+        self.try_len().await.unwrap_or_else(|e| {
+            eprintln!("redis len failed: {}", e);
+            0
+        })
+    }
+}
+
+// an in-process l1 in front of a shared l2, redis outside of tests
+pub struct TieredCache {
+    l1: RuntimeCache,
+    l2: Box<dyn Cache>,
+}
+
+impl TieredCache {
+    pub fn new(l1: RuntimeCache, l2: Box<dyn Cache>) -> TieredCache {
+        TieredCache { l1, l2 }
+    }
+}
+
+#[async_trait]
+impl Cache for TieredCache {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+    async fn set(&self, key: CacheKey, entry: CacheEntry) {
+        self.l2.set(key.clone(), entry.clone()).await;
+        self.l1.set(key, entry).await;
+    }
+    async fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        if let Some(value) = self.l1.get(key).await {
+            return Some(value);
+        }
+        let value = self.l2.get(key).await?;
+        self.l1.set(key.clone(), value.clone()).await;
+        Some(value)
+    }
+    async fn get_aprx(&self, key_aprx: &CacheKey) -> Option<CacheEntry> {
+        if let Some(value) = self.l1.get_aprx(key_aprx).await {
+            return Some(value);
+        }
+        // promoted under the requested key, the short l1 ttl keeps the approximation bounded
+        let value = self.l2.get_aprx(key_aprx).await?;
+        self.l1.set(key_aprx.clone(), value.clone()).await;
+        Some(value)
+    }
+    async fn del(&self, key: &CacheKey) {
+        self.l1.del(key).await;
+        self.l2.del(key).await;
+    }
+    async fn len(&self) -> usize {
+        self.l2.len().await
+    }
+    async fn stats(&self) -> CacheStats {
+        // redis expires l2 entries itself, only l1 evictions are observable
+        self.l1.stats().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{WeatherApiType, WeatherLang, WeatherUnits};
+
+    const HOUR: u64 = 60 * 60;
+    const APRX_WINDOW: u64 = HOUR * 2;
+
+    fn temp(temp: f32) -> domain::WeatherReport {
+        domain::WeatherReport {
+            location: domain::Location {
+                name: "Moscow, Russia".to_string(),
+                latitude: 55.75,
+                longitude: 37.62,
+                tzoffset: 3.0,
+            },
+            current: Some(domain::Conditions {
+                observed_at: 0,
+                temp,
+                humidity: 50.0,
+                pressure: 1000.0,
+                wind_speed: 3.0,
+                conditions: "Clear".to_string(),
+                icon: "clear-day".to_string(),
+            }),
+            days: Vec::new(),
+            hours: Vec::new(),
+            alerts: Vec::new(),
+            description: None,
+            cost: 1.0,
+        }
+    }
+
+    fn entry(temp_value: f32, stored_at: u64, expires_at: u64) -> CacheEntry {
+        CacheEntry {
+            value: temp(temp_value),
+            stored_at,
+            expires_at,
+            stale_until: u64::MAX,
+        }
+    }
+
+    fn ttl(fresh_secs: u64, stale_secs: u64) -> CacheTtl {
+        CacheTtl {
+            fresh_for: Duration::from_secs(fresh_secs),
+            stale_for: Duration::from_secs(stale_secs),
+        }
+    }
+
+    fn key(city: &str, bucket_ts: u64) -> CacheKey {
+        CacheKey {
+            location: city.to_string(),
+            api_type: WeatherApiType::Current,
+            units: WeatherUnits::Metric,
+            lang: WeatherLang::En,
+            bucket_ts,
+        }
+    }
+
+    fn runtime(capacity: usize, ttl: Duration) -> RuntimeCache {
+        RuntimeCache::new(
+            NonZeroUsize::new(capacity).unwrap(),
+            Some(ttl),
+            Duration::from_secs(APRX_WINDOW),
+        )
+    }
+
+    #[test]
+    fn classify_without_entry_is_missing() {
+        assert!(matches!(Freshness::classify(None, 100), Freshness::Missing));
+    }
+
+    #[test]
+    fn classify_before_expiry_is_fresh() {
+        let lookup = Freshness::classify(Some(entry(1.0, 100, 160)), 130);
+        assert!(matches!(lookup, Freshness::Fresh { age: 30, .. }));
+    }
+
+    #[test]
+    fn classify_from_expiry_on_is_stale() {
+        let at_expiry = Freshness::classify(Some(entry(1.0, 100, 160)), 160);
+        assert!(matches!(at_expiry, Freshness::Stale { age: 60, .. }));
+
+        let long_after = Freshness::classify(Some(entry(1.0, 100, 160)), 500);
+        assert!(matches!(long_after, Freshness::Stale { age: 400, .. }));
+    }
+
+    #[test]
+    fn classify_past_stale_window_is_missing() {
+        let entry = CacheEntry {
+            stale_until: 200,
+            ..entry(1.0, 100, 160)
+        };
+        assert!(matches!(
+            Freshness::classify(Some(entry.clone()), 199),
+            Freshness::Stale { .. }
+        ));
+        assert!(matches!(
+            Freshness::classify(Some(entry), 200),
+            Freshness::Missing
+        ));
+    }
+
+    #[test]
+    fn classify_with_clock_behind_store_time_has_zero_age() {
+        let lookup = Freshness::classify(Some(entry(1.0, 100, 160)), 90);
+        assert!(matches!(lookup, Freshness::Fresh { age: 0, .. }));
+    }
+
+    #[tokio::test]
+    async fn lookup_of_new_entry_is_fresh() {
+        let cache = runtime(8, Duration::from_secs(60));
+        let key = key("moscow", 1000);
+        cache
+            .set(key.clone(), CacheEntry::new(temp(1.0), ttl(60, 60)))
+            .await;
+
+        assert!(matches!(cache.lookup(&key).await, Freshness::Fresh { .. }));
+    }
+
+    #[tokio::test]
+    async fn lookup_of_entry_past_fresh_window_is_stale() {
+        let cache = runtime(8, Duration::from_secs(60));
+        let key = key("moscow", 1000);
+        cache
+            .set(key.clone(), CacheEntry::new(temp(1.0), ttl(0, 60)))
+            .await;
+
+        assert!(matches!(cache.lookup(&key).await, Freshness::Stale { .. }));
+    }
+
+    #[tokio::test]
+    async fn lookup_after_backend_ttl_is_missing() {
+        let cache = runtime(8, Duration::ZERO);
+        let key = key("moscow", 1000);
+        cache
+            .set(key.clone(), CacheEntry::new(temp(1.0), ttl(60, 60)))
+            .await;
+
+        assert!(matches!(cache.lookup(&key).await, Freshness::Missing));
+    }
+
+    #[tokio::test]
+    async fn aprx_matches_buckets_on_both_sides() {
+        let cache = runtime(8, Duration::from_secs(60));
+        cache.set(key("moscow", 10_000), entry(1.0, 0, 0)).await;
+
+        let older = cache.get_aprx(&key("moscow", 10_000 - HOUR)).await;
+        let newer = cache.get_aprx(&key("moscow", 10_000 + HOUR)).await;
+        assert_eq!(older.map(|e| e.value.current.unwrap().temp), Some(1.0));
+        assert_eq!(newer.map(|e| e.value.current.unwrap().temp), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn aprx_prefers_the_nearest_bucket() {
+        let cache = runtime(8, Duration::from_secs(60));
+        cache.set(key("moscow", 10_000), entry(1.0, 0, 0)).await;
+        cache.set(key("moscow", 10_900), entry(2.0, 0, 0)).await;
+
+        let found = cache.get_aprx(&key("moscow", 10_600)).await;
+        assert_eq!(found.map(|e| e.value.current.unwrap().temp), Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn aprx_ignores_buckets_outside_the_window() {
+        let cache = runtime(8, Duration::from_secs(60));
+        cache.set(key("moscow", 10_000), entry(1.0, 0, 0)).await;
+
+        let before = cache
+            .get_aprx(&key("moscow", 10_000 - APRX_WINDOW - 1))
+            .await;
+        let after = cache
+            .get_aprx(&key("moscow", 10_000 + APRX_WINDOW + 1))
+            .await;
+        assert!(before.is_none());
+        assert!(after.is_none());
+    }
+
+    #[tokio::test]
+    async fn aprx_ignores_other_series() {
+        let cache = runtime(8, Duration::from_secs(60));
+        cache.set(key("moscow", 10_000), entry(1.0, 0, 0)).await;
+
+        let other_lang = CacheKey {
+            lang: WeatherLang::Ru,
+            ..key("moscow", 10_000)
+        };
+        assert!(cache.get_aprx(&key("london", 10_000)).await.is_none());
+        assert!(cache.get_aprx(&other_lang).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn capacity_overflow_evicts_least_recently_used() {
+        let cache = runtime(2, Duration::from_secs(60));
+        cache.set(key("moscow", 1), entry(1.0, 0, 0)).await;
+        cache.set(key("london", 1), entry(2.0, 0, 0)).await;
+        cache.get(&key("moscow", 1)).await;
+        cache.set(key("paris", 1), entry(3.0, 0, 0)).await;
+
+        assert!(cache.get(&key("moscow", 1)).await.is_some());
+        assert!(cache.get(&key("london", 1)).await.is_none());
+        assert_eq!(cache.stats().await.evicted, 1);
+    }
+
+    #[tokio::test]
+    async fn overwriting_a_key_is_not_an_eviction() {
+        let cache = runtime(1, Duration::from_secs(60));
+        cache.set(key("moscow", 1), entry(1.0, 0, 0)).await;
+        cache.set(key("moscow", 1), entry(2.0, 0, 0)).await;
+
+        assert_eq!(cache.stats().await.evicted, 0);
+        assert_eq!(cache.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn sweep_drops_expired_entries() {
+        let cache = runtime(8, Duration::ZERO);
+        cache.set(key("moscow", 1), entry(1.0, 0, 0)).await;
+
+        assert_eq!(cache.inner.sweep().await, 1);
+        assert_eq!(cache.len().await, 0);
+        assert_eq!(cache.stats().await.expired, 1);
+    }
+
+    #[tokio::test]
+    async fn no_cache_always_misses() {
+        let cache = NoCache;
+        cache.set(key("moscow", 1), entry(1.0, 0, u64::MAX)).await;
+
+        assert!(matches!(
+            cache.lookup(&key("moscow", 1)).await,
+            Freshness::Missing
+        ));
+    }
+
+    fn tiered() -> (TieredCache, RuntimeCache, RuntimeCache) {
+        let (l1, l2) = (
+            runtime(8, Duration::from_secs(60)),
+            runtime(8, Duration::from_secs(60)),
+        );
+        let tiered = TieredCache::new(l1.clone(), Box::new(l2.clone()));
+        (tiered, l1, l2)
+    }
+
+    #[tokio::test]
+    async fn tiered_writes_through_to_both_tiers() {
+        let (tiered, l1, l2) = tiered();
+        tiered.set(key("moscow", 1), entry(1.0, 0, 0)).await;
+
+        assert!(l1.get(&key("moscow", 1)).await.is_some());
+        assert!(l2.get(&key("moscow", 1)).await.is_some());
+        assert_eq!(tiered.len().await, 1);
+    }
+
+    #[tokio::test]
+    async fn tiered_promotes_l2_hits_into_l1() {
+        let (tiered, l1, l2) = tiered();
+        l2.set(key("moscow", 1), entry(1.0, 0, 0)).await;
+
+        assert!(tiered.get(&key("moscow", 1)).await.is_some());
+        assert!(l1.get(&key("moscow", 1)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn tiered_promotes_approximate_hits_under_the_requested_key() {
+        let (tiered, l1, l2) = tiered();
+        l2.set(key("moscow", 10_000), entry(1.0, 0, 0)).await;
+
+        let requested = key("moscow", 10_000 + HOUR);
+        assert!(tiered.get_aprx(&requested).await.is_some());
+        assert!(l1.get(&requested).await.is_some());
+        assert!(l1.get(&key("moscow", 10_000)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn tiered_deletes_from_both_tiers() {
+        let (tiered, l1, l2) = tiered();
+        tiered.set(key("moscow", 1), entry(1.0, 0, 0)).await;
+        tiered.del(&key("moscow", 1)).await;
+
+        assert!(l1.get(&key("moscow", 1)).await.is_none());
+        assert!(l2.get(&key("moscow", 1)).await.is_none());
+    }
 }
@@ -0,0 +1,1008 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::{fmt, num::NonZeroUsize, str::FromStr, time::Duration};
+
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use redis::IntoConnectionInfo;
+use serde::Deserialize;
+
+use crate::models::WeatherApiType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheBackend {
+    Memory,
+    Redis,
+    Tiered,
+    None,
+}
+
+impl FromStr for CacheBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "memory" => Ok(CacheBackend::Memory),
+            "redis" => Ok(CacheBackend::Redis),
+            "tiered" => Ok(CacheBackend::Tiered),
+            "none" => Ok(CacheBackend::None),
+            other => Err(anyhow!(
+                "unknown cache backend '{}', expected memory, redis, tiered or none",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CacheBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheBackend::Memory => write!(f, "memory"),
+            CacheBackend::Redis => write!(f, "redis"),
+            CacheBackend::Tiered => write!(f, "tiered"),
+            CacheBackend::None => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitBackend {
+    Memory,
+    Redis,
+    None,
+}
+
+impl FromStr for RateLimitBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "memory" => Ok(RateLimitBackend::Memory),
+            "redis" => Ok(RateLimitBackend::Redis),
+            "none" => Ok(RateLimitBackend::None),
+            other => Err(anyhow!(
+                "unknown rate limit backend '{}', expected memory, redis or none",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RateLimitBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitBackend::Memory => write!(f, "memory"),
+            RateLimitBackend::Redis => write!(f, "redis"),
+            RateLimitBackend::None => write!(f, "none"),
+        }
+    }
+}
+
+// what a client's requests are counted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientKey {
+    Ip,
+    // the x-api-key header, falling back to the address without one
+    ApiKey,
+}
+
+impl FromStr for ClientKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "ip" => Ok(ClientKey::Ip),
+            "api_key" => Ok(ClientKey::ApiKey),
+            other => Err(anyhow!(
+                "unknown rate limit key '{}', expected ip or api_key",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKey::Ip => write!(f, "ip"),
+            ClientKey::ApiKey => write!(f, "api_key"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderKind {
+    VisualCrossing,
+    OpenWeatherMap,
+    OpenMeteo,
+}
+
+impl FromStr for ProviderKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "visualcrossing" => Ok(ProviderKind::VisualCrossing),
+            "openweathermap" => Ok(ProviderKind::OpenWeatherMap),
+            "openmeteo" => Ok(ProviderKind::OpenMeteo),
+            other => Err(anyhow!(
+                "unknown weather provider '{}', expected visualcrossing, openweathermap or openmeteo",
+                other
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProviderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderKind::VisualCrossing => write!(f, "visualcrossing"),
+            ProviderKind::OpenWeatherMap => write!(f, "openweathermap"),
+            ProviderKind::OpenMeteo => write!(f, "openmeteo"),
+        }
+    }
+}
+
+// entries are served fresh, then stale while a refresh runs, then dropped
+#[derive(Debug, Clone, Copy)]
+pub struct CacheTtl {
+    pub fresh_for: Duration,
+    pub stale_for: Duration,
+}
+
+impl CacheTtl {
+    pub fn total(&self) -> Duration {
+        self.fresh_for + self.stale_for
+    }
+}
+
+const DEFAULT_BIND: &str = "0.0.0.0:3000";
+const DEFAULT_CONFIG_FILE: &str = "weather.toml";
+// a week apart a forecast is no approximation of another
+const MAX_APRX_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const VISUALCROSSING_URL: &str =
+    "https://weather.visualcrossing.com/VisualCrossingWebServices/rest/services/timeline";
+const OPENWEATHERMAP_URL: &str = "https://api.openweathermap.org/data/2.5/weather";
+const OPENWEATHERMAP_FORECAST_URL: &str = "https://api.openweathermap.org/data/2.5/forecast";
+const OPENMETEO_GEOCODING_URL: &str = "https://geocoding-api.open-meteo.com/v1/search";
+const OPENMETEO_FORECAST_URL: &str = "https://api.open-meteo.com/v1/forecast";
+
+#[derive(Debug, Parser)]
+#[command(about = "weather backend")]
+pub struct Args {
+    #[arg(long, help = "toml config file, ./weather.toml is read when present")]
+    pub config: Option<PathBuf>,
+    #[arg(long, help = "print the effective config and exit")]
+    pub print_config: bool,
+    #[arg(long, help = "address to listen on, ip:port")]
+    pub bind: Option<String>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "ordered failover list of providers"
+    )]
+    pub providers: Option<Vec<String>>,
+    #[arg(long, help = "memory, redis, tiered or none")]
+    pub cache_backend: Option<String>,
+    #[arg(long)]
+    pub redis_url: Option<String>,
+    #[arg(long)]
+    pub cache_capacity: Option<usize>,
+    #[arg(long)]
+    pub upstream_timeout_secs: Option<u64>,
+    #[arg(long)]
+    pub history_db: Option<String>,
+    #[arg(long, help = "memory, redis or none")]
+    pub rate_limit_backend: Option<String>,
+    #[arg(long, help = "upstream query cost allowed per utc day")]
+    pub daily_budget: Option<f64>,
+}
+
+// one source of settings, whatever it leaves unset falls through to the one below
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Layer {
+    server: ServerLayer,
+    upstream: UpstreamLayer,
+    cache: CacheLayer,
+    rate_limit: RateLimitLayer,
+    budget: BudgetLayer,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerLayer {
+    bind: Option<String>,
+    history_db: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UpstreamLayer {
+    providers: Option<Vec<String>>,
+    timeout_secs: Option<u64>,
+    breaker_threshold: Option<u32>,
+    breaker_cooldown_secs: Option<u64>,
+    visualcrossing_url: Option<String>,
+    openweathermap_url: Option<String>,
+    openweathermap_forecast_url: Option<String>,
+    openmeteo_geocoding_url: Option<String>,
+    openmeteo_forecast_url: Option<String>,
+    // names of the env vars holding the keys, the keys never go in a file
+    visualcrossing_key_var: Option<String>,
+    openweathermap_key_var: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CacheLayer {
+    backend: Option<String>,
+    redis_url: Option<String>,
+    fresh_secs: Option<u64>,
+    stale_secs: Option<u64>,
+    forecast_fresh_secs: Option<u64>,
+    forecast_stale_secs: Option<u64>,
+    bucket_secs: Option<u64>,
+    aprx_window_secs: Option<u64>,
+    geohash_precision: Option<usize>,
+    l1_ttl_secs: Option<u64>,
+    capacity: Option<usize>,
+    sweep_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RateLimitLayer {
+    backend: Option<String>,
+    key: Option<String>,
+    burst: Option<u32>,
+    per_minute: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BudgetLayer {
+    daily: Option<f64>,
+    degrade_at: Option<f64>,
+}
+
+impl Layer {
+    fn from_file(path: &Path) -> Result<Layer> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("can't read config file {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| anyhow!("config file {}: {}", path.display(), e))
+    }
+
+    fn from_env() -> Result<Layer> {
+        Ok(Layer {
+            server: ServerLayer {
+                bind: env("BIND_ADDR")?,
+                history_db: env("HISTORY_DB")?,
+            },
+            upstream: UpstreamLayer {
+                providers: env::<String>("WEATHER_PROVIDERS")?
+                    .map(|value| value.split(',').map(str::to_string).collect()),
+                timeout_secs: env("UPSTREAM_TIMEOUT_SECS")?,
+                breaker_threshold: env("BREAKER_THRESHOLD")?,
+                breaker_cooldown_secs: env("BREAKER_COOLDOWN_SECS")?,
+                visualcrossing_url: env("VISUALCROSSING_URL")?,
+                openweathermap_url: env("OPENWEATHERMAP_URL")?,
+                openweathermap_forecast_url: env("OPENWEATHERMAP_FORECAST_URL")?,
+                openmeteo_geocoding_url: env("OPENMETEO_GEOCODING_URL")?,
+                openmeteo_forecast_url: env("OPENMETEO_FORECAST_URL")?,
+                visualcrossing_key_var: env("WEATHER_API_KEY_VAR")?,
+                openweathermap_key_var: env("OPENWEATHERMAP_API_KEY_VAR")?,
+            },
+            cache: CacheLayer {
+                backend: env("CACHE_BACKEND")?,
+                redis_url: env("REDIS_URL")?,
+                fresh_secs: env("CACHE_FRESH_SECS")?,
+                stale_secs: env("CACHE_STALE_SECS")?,
+                forecast_fresh_secs: env("FORECAST_FRESH_SECS")?,
+                forecast_stale_secs: env("FORECAST_STALE_SECS")?,
+                bucket_secs: env("CACHE_BUCKET_SECS")?,
+                aprx_window_secs: env("CACHE_APRX_WINDOW_SECS")?,
+                geohash_precision: env("GEOHASH_PRECISION")?,
+                l1_ttl_secs: env("CACHE_L1_TTL_SECS")?,
+                capacity: env("CACHE_CAPACITY")?,
+                sweep_secs: env("CACHE_SWEEP_SECS")?,
+            },
+            rate_limit: RateLimitLayer {
+                backend: env("RATE_LIMIT_BACKEND")?,
+                key: env("RATE_LIMIT_KEY")?,
+                burst: env("RATE_LIMIT_BURST")?,
+                per_minute: env("RATE_LIMIT_PER_MIN")?,
+            },
+            budget: BudgetLayer {
+                daily: env("DAILY_QUERY_BUDGET")?,
+                degrade_at: env("BUDGET_DEGRADE_AT")?,
+            },
+        })
+    }
+
+    fn from_args(args: &Args) -> Layer {
+        Layer {
+            server: ServerLayer {
+                bind: args.bind.clone(),
+                history_db: args.history_db.clone(),
+            },
+            upstream: UpstreamLayer {
+                providers: args.providers.clone(),
+                timeout_secs: args.upstream_timeout_secs,
+                ..UpstreamLayer::default()
+            },
+            cache: CacheLayer {
+                backend: args.cache_backend.clone(),
+                redis_url: args.redis_url.clone(),
+                capacity: args.cache_capacity,
+                ..CacheLayer::default()
+            },
+            rate_limit: RateLimitLayer {
+                backend: args.rate_limit_backend.clone(),
+                ..RateLimitLayer::default()
+            },
+            budget: BudgetLayer {
+                daily: args.daily_budget,
+                ..BudgetLayer::default()
+            },
+        }
+    }
+
+    fn over(self, below: Layer) -> Layer {
+        let (s, b) = (self.server, below.server);
+        let server = ServerLayer {
+            bind: s.bind.or(b.bind),
+            history_db: s.history_db.or(b.history_db),
+        };
+        let (s, b) = (self.upstream, below.upstream);
+        let upstream = UpstreamLayer {
+            providers: s.providers.or(b.providers),
+            timeout_secs: s.timeout_secs.or(b.timeout_secs),
+            breaker_threshold: s.breaker_threshold.or(b.breaker_threshold),
+            breaker_cooldown_secs: s.breaker_cooldown_secs.or(b.breaker_cooldown_secs),
+            visualcrossing_url: s.visualcrossing_url.or(b.visualcrossing_url),
+            openweathermap_url: s.openweathermap_url.or(b.openweathermap_url),
+            openweathermap_forecast_url: s
+                .openweathermap_forecast_url
+                .or(b.openweathermap_forecast_url),
+            openmeteo_geocoding_url: s.openmeteo_geocoding_url.or(b.openmeteo_geocoding_url),
+            openmeteo_forecast_url: s.openmeteo_forecast_url.or(b.openmeteo_forecast_url),
+            visualcrossing_key_var: s.visualcrossing_key_var.or(b.visualcrossing_key_var),
+            openweathermap_key_var: s.openweathermap_key_var.or(b.openweathermap_key_var),
+        };
+        let (s, b) = (self.cache, below.cache);
+        let cache = CacheLayer {
+            backend: s.backend.or(b.backend),
+            redis_url: s.redis_url.or(b.redis_url),
+            fresh_secs: s.fresh_secs.or(b.fresh_secs),
+            stale_secs: s.stale_secs.or(b.stale_secs),
+            forecast_fresh_secs: s.forecast_fresh_secs.or(b.forecast_fresh_secs),
+            forecast_stale_secs: s.forecast_stale_secs.or(b.forecast_stale_secs),
+            bucket_secs: s.bucket_secs.or(b.bucket_secs),
+            aprx_window_secs: s.aprx_window_secs.or(b.aprx_window_secs),
+            geohash_precision: s.geohash_precision.or(b.geohash_precision),
+            l1_ttl_secs: s.l1_ttl_secs.or(b.l1_ttl_secs),
+            capacity: s.capacity.or(b.capacity),
+            sweep_secs: s.sweep_secs.or(b.sweep_secs),
+        };
+        let (s, b) = (self.rate_limit, below.rate_limit);
+        let rate_limit = RateLimitLayer {
+            backend: s.backend.or(b.backend),
+            key: s.key.or(b.key),
+            burst: s.burst.or(b.burst),
+            per_minute: s.per_minute.or(b.per_minute),
+        };
+        let (s, b) = (self.budget, below.budget);
+        let budget = BudgetLayer {
+            daily: s.daily.or(b.daily),
+            degrade_at: s.degrade_at.or(b.degrade_at),
+        };
+        Layer {
+            server,
+            upstream,
+            cache,
+            rate_limit,
+            budget,
+        }
+    }
+}
+
+fn env<T>(name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("{}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+// an empty KEY= in a .env is as good as no key
+pub fn api_key(var: &str) -> Option<String> {
+    usable_key(std::env::var(var).ok())
+}
+
+fn usable_key(key: Option<String>) -> Option<String> {
+    key.map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+}
+
+fn needs_redis(cache_backend: CacheBackend, rate_limit_backend: RateLimitBackend) -> bool {
+    matches!(cache_backend, CacheBackend::Redis | CacheBackend::Tiered)
+        || rate_limit_backend == RateLimitBackend::Redis
+}
+
+// collects every problem instead of stopping at the first, so a bad deploy
+// is fixed in one go
+#[derive(Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn check<T>(&mut self, value: Result<T>, fallback: T) -> T {
+        value.unwrap_or_else(|e| {
+            self.0.push(e.to_string());
+            fallback
+        })
+    }
+
+    fn secs(&mut self, name: &str, value: Option<u64>, default: u64) -> Duration {
+        let secs = value.unwrap_or(default);
+        if secs == 0 {
+            self.0.push(format!("{} must be above zero", name));
+            return Duration::from_secs(default);
+        }
+        Duration::from_secs(secs)
+    }
+
+    fn url(&mut self, name: &str, value: Option<String>, default: &str) -> String {
+        let value = value.unwrap_or_else(|| default.to_string());
+        match reqwest::Url::parse(&value) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => self
+                .0
+                .push(format!("{} '{}' is not an http(s) url", name, value)),
+        }
+        value
+    }
+}
+
+// where each provider is reached, overridable for proxies and test doubles
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub visualcrossing: String,
+    pub openweathermap: String,
+    pub openweathermap_forecast: String,
+    pub openmeteo_geocoding: String,
+    pub openmeteo_forecast: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // the toml file the settings were layered on, if any
+    pub file: Option<PathBuf>,
+    pub bind_addr: SocketAddr,
+    pub providers: Vec<ProviderKind>,
+    pub endpoints: Endpoints,
+    pub visualcrossing_key_var: String,
+    pub openweathermap_key_var: String,
+    pub upstream_timeout: Duration,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: Duration,
+    pub cache_backend: CacheBackend,
+    pub redis_url: String,
+    pub current_ttl: CacheTtl,
+    pub forecast_ttl: CacheTtl,
+    pub bucket_width: Duration,
+    // how far apart two buckets of the same series may be for an approximate hit
+    pub aprx_window: Duration,
+    pub geohash_precision: usize,
+    pub history_db: String,
+    pub l1_ttl: Duration,
+    pub cache_capacity: NonZeroUsize,
+    pub sweep_interval: Duration,
+    pub rate_limit_backend: RateLimitBackend,
+    pub rate_limit_key: ClientKey,
+    pub rate_limit_burst: u32,
+    pub rate_limit_per_minute: u32,
+    // unlimited when unset
+    pub daily_budget: Option<f64>,
+    // share of the daily budget after which only the cache is served
+    pub budget_degrade_at: f64,
+}
+
+impl Config {
+    // defaults, then the toml file, then env vars, then flags
+    pub fn load(args: &Args) -> Result<Config> {
+        let file = match &args.config {
+            Some(path) => Some(path.clone()),
+            None => match std::env::var("WEATHER_CONFIG") {
+                Ok(path) => Some(PathBuf::from(path)),
+                Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
+            },
+        };
+        let file_layer = match &file {
+            Some(path) => Layer::from_file(path)?,
+            None => Layer::default(),
+        };
+        let layer = Layer::from_args(args).over(Layer::from_env()?.over(file_layer));
+        Config::build(layer, file)
+    }
+
+    fn build(layer: Layer, file: Option<PathBuf>) -> Result<Config> {
+        let mut problems = Problems::default();
+        let Layer {
+            server,
+            upstream,
+            cache,
+            rate_limit,
+            budget,
+        } = layer;
+
+        let bind = server.bind.unwrap_or_else(|| DEFAULT_BIND.to_string());
+        let bind_addr = problems.check(
+            bind.parse()
+                .map_err(|_| anyhow!("server.bind '{}' is not an ip:port address", bind)),
+            DEFAULT_BIND.parse()?,
+        );
+        let history_db = server
+            .history_db
+            .unwrap_or_else(|| "history.sqlite3".to_string());
+
+        let providers = match upstream.providers {
+            Some(providers) => providers
+                .iter()
+                .filter_map(|p| problems.check(p.parse().map(Some), None))
+                .collect(),
+            None => vec![ProviderKind::VisualCrossing],
+        };
+        if providers.is_empty() {
+            problems
+                .0
+                .push("upstream.providers must name at least one provider".to_string());
+        }
+        let endpoints = Endpoints {
+            visualcrossing: problems.url(
+                "upstream.visualcrossing_url",
+                upstream.visualcrossing_url,
+                VISUALCROSSING_URL,
+            ),
+            openweathermap: problems.url(
+                "upstream.openweathermap_url",
+                upstream.openweathermap_url,
+                OPENWEATHERMAP_URL,
+            ),
+            openweathermap_forecast: problems.url(
+                "upstream.openweathermap_forecast_url",
+                upstream.openweathermap_forecast_url,
+                OPENWEATHERMAP_FORECAST_URL,
+            ),
+            openmeteo_geocoding: problems.url(
+                "upstream.openmeteo_geocoding_url",
+                upstream.openmeteo_geocoding_url,
+                OPENMETEO_GEOCODING_URL,
+            ),
+            openmeteo_forecast: problems.url(
+                "upstream.openmeteo_forecast_url",
+                upstream.openmeteo_forecast_url,
+                OPENMETEO_FORECAST_URL,
+            ),
+        };
+        let visualcrossing_key_var = upstream
+            .visualcrossing_key_var
+            .unwrap_or_else(|| "WEATHER_API_KEY".to_string());
+        let openweathermap_key_var = upstream
+            .openweathermap_key_var
+            .unwrap_or_else(|| "OPENWEATHERMAP_API_KEY".to_string());
+        for provider in &providers {
+            let var = match provider {
+                ProviderKind::VisualCrossing => &visualcrossing_key_var,
+                ProviderKind::OpenWeatherMap => &openweathermap_key_var,
+                ProviderKind::OpenMeteo => continue,
+            };
+            if api_key(var).is_none() {
+                problems
+                    .0
+                    .push(format!("{} needs an api key in {}", provider, var));
+            }
+        }
+        let upstream_timeout = problems.secs("upstream.timeout_secs", upstream.timeout_secs, 10);
+        let breaker_threshold = upstream.breaker_threshold.unwrap_or(5);
+        if breaker_threshold == 0 {
+            problems
+                .0
+                .push("upstream.breaker_threshold must be above zero".to_string());
+        }
+        let breaker_cooldown = Duration::from_secs(upstream.breaker_cooldown_secs.unwrap_or(30));
+
+        let cache_backend = match cache.backend {
+            Some(backend) => problems.check(backend.parse(), CacheBackend::Memory),
+            None => CacheBackend::Memory,
+        };
+        let redis_url = cache
+            .redis_url
+            .unwrap_or_else(|| "redis://127.0.0.1/".to_string());
+        let rate_limit_backend = match rate_limit.backend {
+            Some(backend) => problems.check(backend.parse(), RateLimitBackend::Memory),
+            None => RateLimitBackend::Memory,
+        };
+        let rate_limit_key = match rate_limit.key {
+            Some(key) => problems.check(key.parse(), ClientKey::Ip),
+            None => ClientKey::Ip,
+        };
+        let rate_limit_burst = rate_limit.burst.unwrap_or(10);
+        let rate_limit_per_minute = rate_limit.per_minute.unwrap_or(30);
+        if rate_limit_burst == 0 || rate_limit_per_minute == 0 {
+            problems
+                .0
+                .push("rate_limit.burst and rate_limit.per_minute must be above zero".to_string());
+        }
+        if needs_redis(cache_backend, rate_limit_backend)
+            && redis_url.as_str().into_connection_info().is_err()
+        {
+            problems.0.push(format!(
+                "cache.redis_url '{}' is not a redis url",
+                redis_url
+            ));
+        }
+        let current_ttl = CacheTtl {
+            fresh_for: problems.secs("cache.fresh_secs", cache.fresh_secs, 30 * 60),
+            stale_for: Duration::from_secs(cache.stale_secs.unwrap_or(90 * 60)),
+        };
+        let forecast_ttl = CacheTtl {
+            fresh_for: problems.secs(
+                "cache.forecast_fresh_secs",
+                cache.forecast_fresh_secs,
+                3 * 60 * 60,
+            ),
+            stale_for: Duration::from_secs(cache.forecast_stale_secs.unwrap_or(6 * 60 * 60)),
+        };
+        let bucket_width = problems.secs("cache.bucket_secs", cache.bucket_secs, 2 * 60 * 60);
+        // zero turns approximate hits off
+        let aprx_window = Duration::from_secs(cache.aprx_window_secs.unwrap_or(2 * 60 * 60));
+        if aprx_window > MAX_APRX_WINDOW {
+            problems.0.push(format!(
+                "cache.aprx_window_secs must be at most {}",
+                MAX_APRX_WINDOW.as_secs()
+            ));
+        }
+        let geohash_precision = cache.geohash_precision.unwrap_or(5);
+        if !(1..=12).contains(&geohash_precision) {
+            problems
+                .0
+                .push("cache.geohash_precision must be between 1 and 12".to_string());
+        }
+        let l1_ttl = problems.secs("cache.l1_ttl_secs", cache.l1_ttl_secs, 5 * 60);
+        let cache_capacity = problems.check(
+            NonZeroUsize::new(cache.capacity.unwrap_or(10_000))
+                .ok_or_else(|| anyhow!("cache.capacity must be above zero")),
+            NonZeroUsize::MIN,
+        );
+        let sweep_interval = problems.secs("cache.sweep_secs", cache.sweep_secs, 60);
+
+        let daily_budget = budget.daily;
+        if daily_budget.is_some_and(|daily| daily <= 0.0 || !daily.is_finite()) {
+            problems
+                .0
+                .push("budget.daily must be above zero".to_string());
+        }
+        let budget_degrade_at = budget.degrade_at.unwrap_or(0.9);
+        if !(budget_degrade_at > 0.0 && budget_degrade_at <= 1.0) {
+            problems
+                .0
+                .push("budget.degrade_at must be above 0 and at most 1".to_string());
+        }
+
+        if !problems.0.is_empty() {
+            return Err(anyhow!("invalid config:\n  {}", problems.0.join("\n  ")));
+        }
+
+        Ok(Config {
+            file,
+            bind_addr,
+            providers,
+            endpoints,
+            visualcrossing_key_var,
+            openweathermap_key_var,
+            upstream_timeout,
+            breaker_threshold,
+            breaker_cooldown,
+            cache_backend,
+            redis_url,
+            current_ttl,
+            forecast_ttl,
+            bucket_width,
+            aprx_window,
+            geohash_precision,
+            history_db,
+            l1_ttl,
+            cache_capacity,
+            sweep_interval,
+            rate_limit_backend,
+            rate_limit_key,
+            rate_limit_burst,
+            rate_limit_per_minute,
+            daily_budget,
+            budget_degrade_at,
+        })
+    }
+
+    // a redis is shared between the replicas, so shared state goes there too
+    pub fn uses_redis(&self) -> bool {
+        needs_redis(self.cache_backend, self.rate_limit_backend)
+    }
+
+    pub fn ttl_for(&self, api_type: &WeatherApiType) -> CacheTtl {
+        match api_type {
+            WeatherApiType::Current => self.current_ttl,
+            WeatherApiType::Forecast => self.forecast_ttl,
+            // past days never change
+            WeatherApiType::Historical => CacheTtl {
+                fresh_for: Duration::MAX,
+                stale_for: Duration::ZERO,
+            },
+        }
+    }
+
+    // how long a backend has to keep the longest lived expiring entry around
+    pub fn cache_ttl(&self) -> Duration {
+        self.current_ttl.total().max(self.forecast_ttl.total())
+    }
+}
+
+// secrets stay out, the redis password is masked and api keys are only named
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let providers: Vec<String> = self.providers.iter().map(|p| p.to_string()).collect();
+        let redis_url = match reqwest::Url::parse(&self.redis_url) {
+            Ok(mut url) if url.password().is_some() => {
+                let _ = url.set_password(Some("***"));
+                url.to_string()
+            }
+            _ => self.redis_url.clone(),
+        };
+        match &self.file {
+            Some(path) => writeln!(f, "config file: {}", path.display())?,
+            None => writeln!(f, "config file: none")?,
+        }
+        writeln!(f, "server.bind = {}", self.bind_addr)?;
+        writeln!(f, "server.history_db = {}", self.history_db)?;
+        writeln!(f, "upstream.providers = {}", providers.join(" -> "))?;
+        writeln!(
+            f,
+            "upstream.timeout_secs = {}",
+            self.upstream_timeout.as_secs()
+        )?;
+        writeln!(f, "upstream.breaker_threshold = {}", self.breaker_threshold)?;
+        writeln!(
+            f,
+            "upstream.breaker_cooldown_secs = {}",
+            self.breaker_cooldown.as_secs()
+        )?;
+        writeln!(
+            f,
+            "upstream.visualcrossing_url = {}",
+            self.endpoints.visualcrossing
+        )?;
+        writeln!(
+            f,
+            "upstream.openweathermap_url = {}",
+            self.endpoints.openweathermap
+        )?;
+        writeln!(
+            f,
+            "upstream.openweathermap_forecast_url = {}",
+            self.endpoints.openweathermap_forecast
+        )?;
+        writeln!(
+            f,
+            "upstream.openmeteo_geocoding_url = {}",
+            self.endpoints.openmeteo_geocoding
+        )?;
+        writeln!(
+            f,
+            "upstream.openmeteo_forecast_url = {}",
+            self.endpoints.openmeteo_forecast
+        )?;
+        writeln!(
+            f,
+            "upstream.visualcrossing_key_var = {}",
+            self.visualcrossing_key_var
+        )?;
+        writeln!(
+            f,
+            "upstream.openweathermap_key_var = {}",
+            self.openweathermap_key_var
+        )?;
+        writeln!(f, "cache.backend = {}", self.cache_backend)?;
+        writeln!(f, "cache.redis_url = {}", redis_url)?;
+        writeln!(
+            f,
+            "cache.fresh_secs = {}",
+            self.current_ttl.fresh_for.as_secs()
+        )?;
+        writeln!(
+            f,
+            "cache.stale_secs = {}",
+            self.current_ttl.stale_for.as_secs()
+        )?;
+        writeln!(
+            f,
+            "cache.forecast_fresh_secs = {}",
+            self.forecast_ttl.fresh_for.as_secs()
+        )?;
+        writeln!(
+            f,
+            "cache.forecast_stale_secs = {}",
+            self.forecast_ttl.stale_for.as_secs()
+        )?;
+        writeln!(f, "cache.bucket_secs = {}", self.bucket_width.as_secs())?;
+        writeln!(f, "cache.aprx_window_secs = {}", self.aprx_window.as_secs())?;
+        writeln!(f, "cache.geohash_precision = {}", self.geohash_precision)?;
+        writeln!(f, "cache.l1_ttl_secs = {}", self.l1_ttl.as_secs())?;
+        writeln!(f, "cache.capacity = {}", self.cache_capacity)?;
+        writeln!(f, "cache.sweep_secs = {}", self.sweep_interval.as_secs())?;
+        writeln!(f, "rate_limit.backend = {}", self.rate_limit_backend)?;
+        writeln!(f, "rate_limit.key = {}", self.rate_limit_key)?;
+        writeln!(f, "rate_limit.burst = {}", self.rate_limit_burst)?;
+        writeln!(f, "rate_limit.per_minute = {}", self.rate_limit_per_minute)?;
+        match self.daily_budget {
+            Some(daily) => writeln!(f, "budget.daily = {}", daily)?,
+            None => writeln!(f, "budget.daily = unlimited")?,
+        }
+        write!(f, "budget.degrade_at = {}", self.budget_degrade_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // open-meteo needs no key, so these don't depend on the test's environment
+    fn layer(toml: &str) -> Layer {
+        let mut layer: Layer = toml::from_str(toml).unwrap();
+        layer
+            .upstream
+            .providers
+            .get_or_insert_with(|| vec!["openmeteo".to_string()]);
+        layer
+    }
+
+    fn problems(layer: Layer) -> String {
+        Config::build(layer, None).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn unset_settings_take_the_defaults() {
+        let config = Config::build(layer(""), None).unwrap();
+        assert_eq!(config.bind_addr, DEFAULT_BIND.parse().unwrap());
+        assert_eq!(config.providers, vec![ProviderKind::OpenMeteo]);
+        assert_eq!(config.cache_backend, CacheBackend::Memory);
+        assert_eq!(config.rate_limit_backend, RateLimitBackend::Memory);
+        assert_eq!(config.endpoints.openmeteo_forecast, OPENMETEO_FORECAST_URL);
+        assert_eq!(config.current_ttl.fresh_for, Duration::from_secs(30 * 60));
+        assert_eq!(config.daily_budget, None);
+        assert!(!config.uses_redis());
+    }
+
+    #[test]
+    fn upper_layers_win_setting_by_setting() {
+        let file = layer(
+            r#"
+            [server]
+            bind = "127.0.0.1:1000"
+            history_db = "file.sqlite3"
+            [cache]
+            capacity = 10
+            fresh_secs = 100
+            "#,
+        );
+        let env = layer(
+            r#"
+            [server]
+            bind = "127.0.0.1:2000"
+            [cache]
+            capacity = 20
+            "#,
+        );
+        let args = layer(
+            r#"
+            [cache]
+            capacity = 30
+            "#,
+        );
+        let config = Config::build(args.over(env.over(file)), None).unwrap();
+        assert_eq!(config.bind_addr, "127.0.0.1:2000".parse().unwrap());
+        assert_eq!(config.history_db, "file.sqlite3");
+        assert_eq!(config.cache_capacity.get(), 30);
+        assert_eq!(config.current_ttl.fresh_for, Duration::from_secs(100));
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let problems = problems(layer(
+            r#"
+            [server]
+            bind = "localhost"
+            [cache]
+            backend = "disk"
+            capacity = 0
+            geohash_precision = 13
+            aprx_window_secs = 691200
+            [upstream]
+            breaker_threshold = 0
+            [budget]
+            degrade_at = 1.5
+            "#,
+        ));
+        for expected in [
+            "cache.aprx_window_secs",
+            "upstream.breaker_threshold",
+            "server.bind 'localhost'",
+            "disk",
+            "cache.capacity",
+            "cache.geohash_precision",
+            "budget.degrade_at",
+        ] {
+            assert!(
+                problems.contains(expected),
+                "{} missing in {}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn redis_url_is_only_checked_when_redis_is_used() {
+        let unused = layer(
+            r#"
+            [cache]
+            redis_url = "not a url"
+            "#,
+        );
+        assert!(Config::build(unused, None).is_ok());
+        let used = layer(
+            r#"
+            [cache]
+            redis_url = "not a url"
+            [rate_limit]
+            backend = "redis"
+            "#,
+        );
+        assert!(problems(used).contains("cache.redis_url"));
+    }
+
+    #[test]
+    fn providers_without_their_key_are_reported() {
+        let problems = problems(layer(
+            r#"
+            [upstream]
+            providers = ["openmeteo", "visualcrossing"]
+            visualcrossing_key_var = "WEATHER_TEST_KEY_THAT_IS_NEVER_SET"
+            "#,
+        ));
+        assert!(problems.contains("needs an api key in WEATHER_TEST_KEY_THAT_IS_NEVER_SET"));
+    }
+
+    #[test]
+    fn blank_keys_count_as_missing() {
+        assert_eq!(usable_key(None), None);
+        assert_eq!(usable_key(Some("".to_string())), None);
+        assert_eq!(usable_key(Some("  ".to_string())), None);
+        assert_eq!(
+            usable_key(Some(" key\n".to_string())),
+            Some("key".to_string())
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(toml::from_str::<Layer>("[cache]\nttl = 5").is_err());
+        assert!(toml::from_str::<Layer>("[caches]\nbackend = \"memory\"").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+
+use axum::Json;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+
+// everything a request can fail with. `code` is part of the api, the frontend
+// branches on it, so never rename one
+#[derive(Debug, Clone)]
+pub enum WeatherError {
+    BadRequest(String),
+    // this client is over its request budget, seconds until it may retry
+    RateLimited(u64),
+    LocationNotFound(String),
+    // the upstream rejected our api key
+    UpstreamAuth(String),
+    // seconds, when the upstream said how long to back off
+    UpstreamRateLimited(Option<u64>),
+    // the upstream answered with json we can't read
+    UpstreamSchema(String),
+    // unreachable, timing out, 5xx or behind an open circuit
+    UpstreamUnavailable(String),
+    // today's upstream budget is spent and nothing usable is cached
+    BudgetExhausted,
+    Internal(String),
+}
+
+impl WeatherError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            WeatherError::BadRequest(_) => "bad_request",
+            WeatherError::RateLimited(_) => "rate_limited",
+            WeatherError::LocationNotFound(_) => "location_not_found",
+            WeatherError::UpstreamAuth(_) => "upstream_auth_failed",
+            WeatherError::UpstreamRateLimited(_) => "upstream_rate_limited",
+            WeatherError::UpstreamSchema(_) => "upstream_schema_mismatch",
+            WeatherError::UpstreamUnavailable(_) => "upstream_unavailable",
+            WeatherError::BudgetExhausted => "upstream_budget_exhausted",
+            WeatherError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            WeatherError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            WeatherError::LocationNotFound(_) => StatusCode::NOT_FOUND,
+            WeatherError::UpstreamAuth(_) | WeatherError::UpstreamSchema(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            WeatherError::RateLimited(_) | WeatherError::UpstreamRateLimited(_) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            WeatherError::UpstreamUnavailable(_) | WeatherError::BudgetExhausted => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            WeatherError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // provider errors arrive as anyhow, typed ones are kept, transport and
+    // parsing failures are recognised, the rest is the upstream misbehaving
+    pub fn from_upstream(e: anyhow::Error) -> WeatherError {
+        let e = match e.downcast::<WeatherError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        // the url carries the api key, it must not reach a response
+        let e = match e.downcast::<reqwest::Error>() {
+            Ok(e) => return WeatherError::UpstreamUnavailable(e.without_url().to_string()),
+            Err(e) => e,
+        };
+        if e.is::<serde_json::Error>() {
+            return WeatherError::UpstreamSchema(e.to_string());
+        }
+        WeatherError::UpstreamUnavailable(e.to_string())
+    }
+}
+
+impl fmt::Display for WeatherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WeatherError::BadRequest(message) => write!(f, "{}", message),
+            WeatherError::RateLimited(retry_after) => {
+                write!(f, "too many requests, retry in {} seconds", retry_after)
+            }
+            WeatherError::LocationNotFound(location) => {
+                write!(f, "location '{}' not found", location)
+            }
+            WeatherError::UpstreamAuth(provider) => {
+                write!(f, "{} rejected the configured api key", provider)
+            }
+            WeatherError::UpstreamRateLimited(_) => {
+                write!(f, "weather providers are rate limiting us, try again later")
+            }
+            WeatherError::UpstreamSchema(message) => {
+                write!(f, "unexpected upstream response: {}", message)
+            }
+            WeatherError::UpstreamUnavailable(message) => {
+                write!(f, "weather providers unavailable: {}", message)
+            }
+            WeatherError::BudgetExhausted => write!(
+                f,
+                "today's weather provider budget is spent, only cached locations are served"
+            ),
+            WeatherError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for WeatherError {}
+
+impl IntoResponse for WeatherError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({"error": self.to_string(), "code": self.code()}));
+        match self {
+            WeatherError::RateLimited(retry_after)
+            | WeatherError::UpstreamRateLimited(Some(retry_after)) => (
+                self.status(),
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                body,
+            )
+                .into_response(),
+            _ => (self.status(), body).into_response(),
+        }
+    }
+}
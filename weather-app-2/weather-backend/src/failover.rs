@@ -0,0 +1,433 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::api::WeatherProvider;
+use crate::error::WeatherError;
+use crate::metrics;
+use crate::models::domain::WeatherReport;
+use crate::models::{WeatherApiType, WeatherQuery};
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    // a single probe is let through, another one only if it never reported back
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            threshold: threshold.max(1),
+            cooldown,
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if now >= until => {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::Open { .. } => false,
+            BreakerState::HalfOpen { since } if now >= since + self.cooldown => {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn on_success(&self) {
+        *self.state.lock().unwrap() = BreakerState::Closed { failures: 0 };
+    }
+
+    pub fn on_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let open = BreakerState::Open {
+            until: Instant::now() + self.cooldown,
+        };
+        *state = match *state {
+            BreakerState::Closed { failures } if failures + 1 < self.threshold => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => open,
+        };
+    }
+}
+
+pub struct FailoverChain {
+    providers: Vec<(Arc<dyn WeatherProvider>, CircuitBreaker)>,
+    timeout: Duration,
+}
+
+impl FailoverChain {
+    pub fn new(
+        providers: Vec<Arc<dyn WeatherProvider>>,
+        timeout: Duration,
+        threshold: u32,
+        cooldown: Duration,
+    ) -> FailoverChain {
+        FailoverChain {
+            providers: providers
+                .into_iter()
+                .map(|provider| (provider, CircuitBreaker::new(threshold, cooldown)))
+                .collect(),
+            timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl WeatherProvider for FailoverChain {
+    fn name(&self) -> &'static str {
+        "failover"
+    }
+    fn supports(&self, api_type: &WeatherApiType) -> bool {
+        self.providers
+            .iter()
+            .any(|(provider, _)| provider.supports(api_type))
+    }
+    async fn fetch(&self, query: &WeatherQuery) -> Result<WeatherReport> {
+        let mut errors = Vec::new();
+        let mut kinds = Vec::new();
+        for (provider, breaker) in &self.providers {
+            if !provider.supports(&query.api_type) {
+                continue;
+            }
+            if !breaker.try_acquire() {
+                errors.push(format!("{}: circuit open", provider.name()));
+                kinds.push(WeatherError::UpstreamUnavailable(
+                    "circuit open".to_string(),
+                ));
+                continue;
+            }
+            let started = Instant::now();
+            match tokio::time::timeout(self.timeout, provider.fetch(query)).await {
+                Ok(Ok(mut report)) => {
+                    breaker.on_success();
+                    metrics::record_upstream(provider.name(), "ok", started.elapsed());
+                    // past the chain a report's cost is what the budget is charged
+                    if !provider.billed() {
+                        report.cost = 0.0;
+                    }
+                    metrics::record_cost(provider.name(), report.cost);
+                    return Ok(report);
+                }
+                Ok(Err(e)) => match WeatherError::from_upstream(e) {
+                    // the upstream is healthy, the city is just unknown
+                    WeatherError::LocationNotFound(city) => {
+                        breaker.on_success();
+                        metrics::record_upstream(
+                            provider.name(),
+                            "location_not_found",
+                            started.elapsed(),
+                        );
+                        return Err(WeatherError::LocationNotFound(city).into());
+                    }
+                    e => {
+                        breaker.on_failure();
+                        metrics::record_upstream(provider.name(), e.code(), started.elapsed());
+                        eprintln!("provider {} failed: {}", provider.name(), e);
+                        errors.push(failure(provider.name(), &e));
+                        kinds.push(e);
+                    }
+                },
+                Err(_) => {
+                    breaker.on_failure();
+                    metrics::record_upstream(provider.name(), "timeout", started.elapsed());
+                    eprintln!("provider {} timed out", provider.name());
+                    errors.push(format!("{}: timed out", provider.name()));
+                    kinds.push(WeatherError::UpstreamUnavailable("timed out".to_string()));
+                }
+            }
+        }
+        let Some(last) = kinds.pop() else {
+            return Err(WeatherError::UpstreamUnavailable(format!(
+                "no configured provider serves {} queries",
+                query.api_type
+            ))
+            .into());
+        };
+        // when every provider failed the same way that is the answer, e.g. all rate limited
+        if kinds.iter().all(|e| e.code() == last.code()) {
+            return Err(last.into());
+        }
+        Err(WeatherError::UpstreamUnavailable(errors.join("; ")).into())
+    }
+}
+
+// one provider's part of the combined error. the messages built in api.rs
+// already name the provider and go in as they are, without the variant's lead-in
+fn failure(provider: &str, e: &WeatherError) -> String {
+    match e {
+        WeatherError::UpstreamUnavailable(message) | WeatherError::UpstreamSchema(message) => {
+            message.clone()
+        }
+        WeatherError::UpstreamAuth(_) => e.to_string(),
+        e => format!("{}: {}", provider, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::models::domain;
+    use crate::models::{Place, WeatherLang};
+
+    const LONG: Duration = Duration::from_secs(60);
+    const SHORT: Duration = Duration::from_millis(20);
+
+    fn report(name: &str) -> WeatherReport {
+        WeatherReport {
+            location: domain::Location {
+                name: name.to_string(),
+                latitude: 55.75,
+                longitude: 37.62,
+                tzoffset: 3.0,
+            },
+            current: None,
+            days: Vec::new(),
+            hours: Vec::new(),
+            alerts: Vec::new(),
+            description: None,
+            cost: 1.0,
+        }
+    }
+
+    fn query() -> WeatherQuery {
+        WeatherQuery {
+            place: Place::City("moscow".to_string()),
+            api_type: WeatherApiType::Current,
+            lang: WeatherLang::En,
+            dates: None,
+        }
+    }
+
+    struct Stub {
+        name: &'static str,
+        // None answers with a report
+        error: Option<WeatherError>,
+        billed: bool,
+        calls: AtomicUsize,
+    }
+
+    impl Stub {
+        fn ok(name: &'static str) -> Arc<Stub> {
+            Arc::new(Stub {
+                name,
+                error: None,
+                billed: true,
+                calls: AtomicUsize::new(0),
+            })
+        }
+
+        fn failing(name: &'static str, error: WeatherError) -> Arc<Stub> {
+            Arc::new(Stub {
+                name,
+                error: Some(error),
+                billed: true,
+                calls: AtomicUsize::new(0),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::Relaxed)
+        }
+    }
+
+    #[async_trait]
+    impl WeatherProvider for Stub {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+        fn billed(&self) -> bool {
+            self.billed
+        }
+        async fn fetch(&self, _query: &WeatherQuery) -> Result<WeatherReport> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match &self.error {
+                Some(e) => Err(e.clone().into()),
+                None => Ok(report(self.name)),
+            }
+        }
+    }
+
+    fn chain(providers: &[Arc<Stub>], threshold: u32) -> FailoverChain {
+        FailoverChain::new(
+            providers
+                .iter()
+                .map(|stub| stub.clone() as Arc<dyn WeatherProvider>)
+                .collect(),
+            LONG,
+            threshold,
+            LONG,
+        )
+    }
+
+    fn unavailable() -> WeatherError {
+        WeatherError::UpstreamUnavailable("down".to_string())
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold_failures() {
+        let breaker = CircuitBreaker::new(2, LONG);
+        assert!(breaker.try_acquire());
+        breaker.on_failure();
+        assert!(breaker.try_acquire());
+        breaker.on_failure();
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn breaker_success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, LONG);
+        breaker.on_failure();
+        breaker.on_success();
+        breaker.on_failure();
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn breaker_lets_one_probe_through_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, SHORT);
+        breaker.on_failure();
+        assert!(!breaker.try_acquire());
+        std::thread::sleep(SHORT * 2);
+        assert!(breaker.try_acquire());
+        // the probe is still out
+        assert!(!breaker.try_acquire());
+        breaker.on_success();
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn breaker_failed_probe_reopens() {
+        let breaker = CircuitBreaker::new(1, SHORT);
+        breaker.on_failure();
+        std::thread::sleep(SHORT * 2);
+        assert!(breaker.try_acquire());
+        breaker.on_failure();
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn breaker_rearms_a_probe_that_never_reported() {
+        let breaker = CircuitBreaker::new(1, SHORT);
+        breaker.on_failure();
+        std::thread::sleep(SHORT * 2);
+        assert!(breaker.try_acquire());
+        std::thread::sleep(SHORT * 2);
+        assert!(breaker.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn chain_falls_through_to_the_next_provider() {
+        let (first, second) = (Stub::failing("first", unavailable()), Stub::ok("second"));
+        let report = chain(&[first.clone(), second.clone()], 5)
+            .fetch(&query())
+            .await
+            .unwrap();
+        assert_eq!(report.location.name, "second");
+        assert_eq!((first.calls(), second.calls()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn chain_stops_on_location_not_found() {
+        let first = Stub::failing(
+            "first",
+            WeatherError::LocationNotFound("moscow".to_string()),
+        );
+        let second = Stub::ok("second");
+        let e = chain(&[first.clone(), second.clone()], 5)
+            .fetch(&query())
+            .await
+            .unwrap_err();
+        assert_eq!(WeatherError::from_upstream(e).code(), "location_not_found");
+        assert_eq!(second.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn chain_returns_the_shared_error_when_all_fail_alike() {
+        let limited = || WeatherError::UpstreamRateLimited(Some(5));
+        let providers = [
+            Stub::failing("first", limited()),
+            Stub::failing("second", limited()),
+        ];
+        let e = chain(&providers, 5).fetch(&query()).await.unwrap_err();
+        assert!(matches!(
+            WeatherError::from_upstream(e),
+            WeatherError::UpstreamRateLimited(Some(5))
+        ));
+    }
+
+    #[tokio::test]
+    async fn chain_reports_unavailable_when_failures_differ() {
+        let providers = [
+            Stub::failing("first", WeatherError::UpstreamRateLimited(None)),
+            Stub::failing("second", WeatherError::UpstreamAuth("second".to_string())),
+        ];
+        let e = chain(&providers, 5).fetch(&query()).await.unwrap_err();
+        assert_eq!(
+            WeatherError::from_upstream(e).code(),
+            "upstream_unavailable"
+        );
+    }
+
+    #[tokio::test]
+    async fn chain_names_each_failure_once() {
+        let providers = [
+            Stub::failing(
+                "first",
+                WeatherError::UpstreamUnavailable("first timed out".to_string()),
+            ),
+            Stub::failing("second", WeatherError::UpstreamAuth("second".to_string())),
+        ];
+        let e = chain(&providers, 5).fetch(&query()).await.unwrap_err();
+        assert_eq!(
+            WeatherError::from_upstream(e).to_string(),
+            "weather providers unavailable: first timed out; \
+             second rejected the configured api key"
+        );
+    }
+
+    #[tokio::test]
+    async fn chain_skips_a_provider_behind_an_open_circuit() {
+        let (first, second) = (Stub::failing("first", unavailable()), Stub::ok("second"));
+        let chain = chain(&[first.clone(), second.clone()], 1);
+        chain.fetch(&query()).await.unwrap();
+        chain.fetch(&query()).await.unwrap();
+        assert_eq!((first.calls(), second.calls()), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn chain_zeroes_the_cost_of_unbilled_providers() {
+        let free = Arc::new(Stub {
+            name: "free",
+            error: None,
+            billed: false,
+            calls: AtomicUsize::new(0),
+        });
+        let report = chain(&[free], 5).fetch(&query()).await.unwrap();
+        assert_eq!(report.cost, 0.0);
+    }
+}
@@ -1,58 +1,608 @@
-use std::sync::{Arc, RwLock};
+use std::collections::BTreeMap;
 
 use axum::extract::State;
-use axum::response::Html;
+use axum::http::{HeaderMap, HeaderName, header};
+use axum::response::{Html, Response};
 use axum::{Json, response::IntoResponse};
-use serde_json::json;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
 
-use crate::api;
-use crate::cache::{Cache, CacheService, RuntimeCache};
-use crate::models::{CacheKey, FormCity};
+use crate::cache::{Cache, CacheEntry, Freshness, now_ts};
+use crate::error::WeatherError;
+use crate::extract::{self, Path, Query};
+use crate::metrics;
+use crate::models::api::{self, HourField};
+use crate::models::domain::WeatherReport;
+use crate::models::{
+    AlertParams, CacheKey, ForecastParams, FormCity, HistoricalParams, HistoryParams, HourlyParams,
+    LangParams, Place, WeatherApiType, WeatherLang, WeatherQuery, WeatherUnits,
+};
+use crate::state::{AppState, FetchResult};
+use crate::storage::Record;
+
+const X_CACHE_STATUS: HeaderName = HeaderName::from_static("x-cache-status");
+const DEFAULT_FORECAST_DAYS: usize = 7;
+// the longest range every provider can answer
+const MAX_FORECAST_DAYS: usize = 15;
+const DEFAULT_FORECAST_HOURS: usize = 24;
+const DEFAULT_HISTORY_SECS: u64 = 7 * 24 * 60 * 60;
+// upstream bills historical queries per day
+const MAX_HISTORICAL_DAYS: i64 = 31;
+const DAY_SECS: i64 = 24 * 60 * 60;
 
 pub async fn get_homepage() -> impl IntoResponse {
     Html(include_str!("../../index.html")).into_response()
 }
 
 pub async fn get_current_temperature(
-    State(cache): State<Arc<RwLock<CacheService<RuntimeCache>>>>,
-    Json(form): Json<FormCity>,
-) -> impl IntoResponse {
-    let cache_key = CacheKey {
-        city: form.city.clone(),
-        timestamp: form.timestamp,
+    State(state): State<AppState>,
+    Query(lang): Query<LangParams>,
+    headers: HeaderMap,
+    extract::Json(form): extract::Json<FormCity>,
+) -> Result<Response, WeatherError> {
+    let query = WeatherQuery {
+        place: form.place().map_err(WeatherError::BadRequest)?,
+        api_type: WeatherApiType::Current,
+        lang: negotiate_lang(lang.lang.as_deref(), &headers),
+        dates: None,
+    };
+    let cached = cached_report(&state, query).await?;
+    let prepared = cached
+        .report(form.units)
+        .prepared_temp(now_ts())
+        .ok_or_else(|| {
+            WeatherError::UpstreamSchema("no current conditions in the response".to_string())
+        })?;
+    Ok(cached.respond(prepared))
+}
+
+pub async fn get_forecast(
+    State(state): State<AppState>,
+    Query(params): Query<ForecastParams>,
+    headers: HeaderMap,
+) -> Result<Response, WeatherError> {
+    let days = params
+        .days
+        .unwrap_or(DEFAULT_FORECAST_DAYS)
+        .clamp(1, MAX_FORECAST_DAYS);
+    let query = WeatherQuery {
+        place: Place::City(params.city),
+        api_type: WeatherApiType::Forecast,
+        lang: negotiate_lang(params.lang.as_deref(), &headers),
+        dates: None,
+    };
+    let cached = cached_report(&state, query).await?;
+    let forecast = cached.report(params.units).forecast(days);
+    Ok(cached.respond(forecast))
+}
+
+// served from the same cached report as the daily forecast
+pub async fn get_hourly_forecast(
+    State(state): State<AppState>,
+    Query(params): Query<HourlyParams>,
+    headers: HeaderMap,
+) -> Result<Response, WeatherError> {
+    let hours = params
+        .hours
+        .unwrap_or(DEFAULT_FORECAST_HOURS)
+        .clamp(1, MAX_FORECAST_DAYS * 24);
+    let fields = match params.fields.as_deref() {
+        Some(fields) => fields
+            .split(',')
+            .map(str::parse)
+            .collect::<anyhow::Result<Vec<HourField>>>()
+            .map_err(|e| WeatherError::BadRequest(e.to_string()))?,
+        None => HourField::ALL.to_vec(),
+    };
+    let query = WeatherQuery {
+        place: Place::City(params.city),
+        api_type: WeatherApiType::Forecast,
+        lang: negotiate_lang(params.lang.as_deref(), &headers),
+        dates: None,
+    };
+    let cached = cached_report(&state, query).await?;
+    let hourly = cached.report(params.units).hourly(now_ts(), hours, &fields);
+    Ok(cached.respond(hourly))
+}
+
+// alerts come with the current conditions, no separate upstream call
+pub async fn get_alerts(
+    State(state): State<AppState>,
+    Query(params): Query<AlertParams>,
+    headers: HeaderMap,
+) -> Result<Response, WeatherError> {
+    let lang = negotiate_lang(params.lang.as_deref(), &headers);
+    let query = WeatherQuery {
+        place: Place::City(params.city),
+        api_type: WeatherApiType::Current,
+        lang,
+        dates: None,
+    };
+    let cached = cached_report(&state, query).await?;
+    // an explicit ?lang= also filters, by the language it negotiated to
+    let alerts = cached.entry.value.alerts(
+        now_ts(),
+        params.active.unwrap_or(true),
+        params.lang.is_some().then_some(lang),
+    );
+    Ok(cached.respond(alerts))
+}
+
+// only what was fetched through this server, never an upstream call
+pub async fn get_history(
+    State(state): State<AppState>,
+    Query(params): Query<HistoryParams>,
+) -> Result<Response, WeatherError> {
+    let to = params.to.unwrap_or_else(now_ts);
+    let from = params
+        .from
+        .unwrap_or_else(|| to.saturating_sub(DEFAULT_HISTORY_SECS));
+    let location = state
+        .locations
+        .resolve(&Place::City(params.city))
+        .await
+        .map_err(|e| WeatherError::BadRequest(e.to_string()))?;
+    let observations = state
+        .storage
+        .history(&location.id, &location.place.to_string(), from, to)
+        .await
+        .map_err(|e| WeatherError::Internal(e.to_string()))?;
+    Ok(Json(api::History {
+        location: location.place.to_string(),
+        observations: observations
+            .into_iter()
+            .map(|observation| observation.in_units(params.units))
+            .collect(),
+    })
+    .into_response())
+}
+
+pub async fn get_metrics(State(state): State<AppState>) -> Result<Response, WeatherError> {
+    metrics::record_cache(state.cache.name(), state.cache.stats().await);
+    let body = metrics::render().map_err(|e| WeatherError::Internal(e.to_string()))?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response())
+}
+
+pub async fn get_budget(State(state): State<AppState>) -> Response {
+    Json(state.budget.status().await).into_response()
+}
+
+// past days are cached one entry per day, only the days not cached yet are fetched
+pub async fn get_historical(
+    State(state): State<AppState>,
+    Path((city, start, end)): Path<(String, String, String)>,
+    Query(params): Query<HistoricalParams>,
+    headers: HeaderMap,
+) -> Result<Response, WeatherError> {
+    let lang = negotiate_lang(params.lang.as_deref(), &headers);
+    let query = WeatherQuery {
+        place: Place::City(city),
+        api_type: WeatherApiType::Historical,
+        lang,
+        dates: Some(parse_range(&start, &end).map_err(WeatherError::BadRequest)?),
+    };
+    let (report, cache_status) = historical_report(&state, query).await?;
+    Ok((
+        [(X_CACHE_STATUS, cache_status)],
+        language_headers(lang),
+        Json(report.in_units(params.units).forecast(report.days.len())),
+    )
+        .into_response())
+}
+
+fn parse_range(start: &str, end: &str) -> Result<(NaiveDate, NaiveDate), String> {
+    let parse = |date: &str| {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|_| format!("'{}' is not a yyyy-mm-dd date", date))
+    };
+    let (start, end) = (parse(start)?, parse(end)?);
+    if start > end {
+        return Err("start is after end".to_string());
+    }
+    // days are keyed by their unix timestamp, which starts there
+    if start < DateTime::UNIX_EPOCH.date_naive() {
+        return Err("historical ranges start on 1970-01-01 at the earliest".to_string());
+    }
+    if end >= Utc::now().date_naive() {
+        return Err(
+            "historical ranges end by yesterday, see /api/forecast from today on".to_string(),
+        );
+    }
+    if (end - start).num_days() >= MAX_HISTORICAL_DAYS {
+        return Err(format!("at most {} days per query", MAX_HISTORICAL_DAYS));
+    }
+    Ok((start, end))
+}
+
+async fn historical_report(
+    state: &AppState,
+    query: WeatherQuery,
+) -> Result<(WeatherReport, &'static str), WeatherError> {
+    let location = state
+        .locations
+        .resolve(&query.place)
+        .await
+        .map_err(|e| WeatherError::BadRequest(e.to_string()))?;
+    let query = WeatherQuery {
+        place: location.place,
+        ..query
     };
+    let (start, end) = query
+        .dates
+        .ok_or_else(|| WeatherError::Internal("historical query without dates".to_string()))?;
 
-    if let Ok(reader) = cache.read() {
-        println!("reader len: {}", reader.len());
-        if let Some(pt) = reader.get_aprx(&cache_key) {
-            println!("something really found");
-            println!("{:#?}", pt);
-            return pt.into_response();
+    let mut days = BTreeMap::new();
+    let mut missing = Vec::new();
+    for date in start.iter_days().take_while(|date| *date <= end) {
+        let key = historical_key(&location.id, query.lang, date);
+        match state.cache.lookup(&key).await {
+            Freshness::Fresh { entry, .. } => {
+                days.insert(date, entry.value);
+            }
+            _ => missing.push(date),
         }
     }
+    let cache_status = match (missing.is_empty(), days.is_empty()) {
+        (true, _) => "fresh",
+        (false, true) => "miss",
+        (false, false) => "partial",
+    };
 
-    let response_vc = match api::fetch_weather_api(&form.city).await {
-        Ok(response) => response,
-        Err(e) => {
-            return (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"error": e.to_string()})),
-            )
-                .into_response();
+    for dates in contiguous_runs(&missing) {
+        if state.budget.degraded().await {
+            return Err(WeatherError::BudgetExhausted);
         }
+        let run = WeatherQuery {
+            dates: Some(dates),
+            ..query.clone()
+        };
+        let report = state
+            .provider
+            .fetch(&run)
+            .await
+            .map_err(WeatherError::from_upstream)?;
+        state.budget.charge(report.cost).await;
+        let location_id = match &run.place {
+            Place::City(name) => state.locations.learn(name, &report.location).await,
+            Place::Coords { .. } => location.id.clone(),
+        };
+        let cost = report.cost / report.days.len().max(1) as f32;
+        for day in &report.days {
+            let date = NaiveDate::parse_from_str(&day.date, "%Y-%m-%d").map_err(|_| {
+                WeatherError::UpstreamSchema(format!("'{}' is not a yyyy-mm-dd date", day.date))
+            })?;
+            let day_report = WeatherReport {
+                location: report.location.clone(),
+                current: None,
+                days: vec![day.clone()],
+                hours: Vec::new(),
+                alerts: Vec::new(),
+                description: None,
+                cost,
+            };
+            // yesterday may still be running in the location's timezone
+            let ttl = if day_is_over(date, report.location.tzoffset, now_ts()) {
+                state.config.ttl_for(&WeatherApiType::Historical)
+            } else {
+                state.config.ttl_for(&WeatherApiType::Forecast)
+            };
+            let key = historical_key(&location_id, run.lang, date);
+            state
+                .cache
+                .set(key, CacheEntry::new(day_report.clone(), ttl))
+                .await;
+            if (dates.0..=dates.1).contains(&date) {
+                days.insert(date, day_report);
+            }
+        }
+    }
+
+    // a gap would pass for the complete range otherwise
+    if let Some(date) = start
+        .iter_days()
+        .take_while(|date| *date <= end)
+        .find(|date| !days.contains_key(date))
+    {
+        return Err(WeatherError::UpstreamSchema(format!(
+            "no data for {} in the response",
+            date
+        )));
+    }
+    let mut reports = days.into_values();
+    let Some(first) = reports.next() else {
+        return Err(WeatherError::Internal("empty historical range".to_string()));
     };
+    let report = reports.fold(first, |mut report, day| {
+        report.days.extend(day.days);
+        report.cost += day.cost;
+        report
+    });
+    Ok((report, cache_status))
+}
 
-    let fts = form.timestamp;
-    let rts = response_vc.get_current_timestamp();
-    println!("{} - {} = {}", fts, rts, fts.checked_sub(rts).unwrap_or(0));
+fn historical_key(location: &str, lang: WeatherLang, date: NaiveDate) -> CacheKey {
+    CacheKey {
+        location: location.to_string(),
+        api_type: WeatherApiType::Historical,
+        units: WeatherUnits::Metric,
+        lang,
+        // parse_range turns away dates before 1970
+        bucket_ts: date_start(date) as u64,
+    }
+}
+
+// utc midnight of the date, local offsets are applied by the callers
+fn date_start(date: NaiveDate) -> i64 {
+    date.and_hms_opt(0, 0, 0)
+        .map(|midnight| midnight.and_utc().timestamp())
+        .unwrap_or(0)
+}
 
-    let pt = Json(response_vc.get_prepared_temp());
+fn day_is_over(date: NaiveDate, tzoffset_hours: f32, now: u64) -> bool {
+    let offset = (tzoffset_hours * 3600.0).round() as i64;
+    now as i64 >= date_start(date) + DAY_SECS - offset
+}
 
-    {
-        if let Ok(mut writer) = cache.write() {
-            writer.set(cache_key, pt.clone());
+// sorted dates into inclusive ranges of consecutive days
+fn contiguous_runs(dates: &[NaiveDate]) -> Vec<(NaiveDate, NaiveDate)> {
+    let mut runs: Vec<(NaiveDate, NaiveDate)> = Vec::new();
+    for &date in dates {
+        match runs.last_mut() {
+            Some((_, end)) if end.succ_opt() == Some(date) => *end = date,
+            _ => runs.push((date, date)),
         }
     }
+    runs
+}
+
+struct Cached {
+    entry: CacheEntry,
+    status: &'static str,
+    age: u64,
+    lang: WeatherLang,
+}
 
-    pt.into_response()
+impl Cached {
+    fn report(&self, units: WeatherUnits) -> WeatherReport {
+        self.entry.value.in_units(units)
+    }
+
+    fn respond<T: Serialize>(&self, body: T) -> Response {
+        (
+            [
+                (X_CACHE_STATUS, self.status.to_string()),
+                (header::AGE, self.age.to_string()),
+            ],
+            language_headers(self.lang),
+            Json(body),
+        )
+            .into_response()
+    }
+}
+
+// the body depends on Accept-Language, so caches along the way have to key on it too
+fn language_headers(lang: WeatherLang) -> [(HeaderName, String); 2] {
+    [
+        (header::VARY, header::ACCEPT_LANGUAGE.to_string()),
+        (header::CONTENT_LANGUAGE, lang.to_string()),
+    ]
+}
+
+fn negotiate_lang(param: Option<&str>, headers: &HeaderMap) -> WeatherLang {
+    let accept_language = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok());
+    WeatherLang::negotiate(param, accept_language)
+}
+
+async fn cached_report(state: &AppState, query: WeatherQuery) -> Result<Cached, WeatherError> {
+    let location = state
+        .locations
+        .resolve(&query.place)
+        .await
+        .map_err(|e| WeatherError::BadRequest(e.to_string()))?;
+    // a name learned elsewhere comes with its timezone, the buckets need it to line up
+    if let Some(tzoffset) = location.tzoffset {
+        state.buckets.learn_offset(&location.id, tzoffset);
+    }
+    let query = WeatherQuery {
+        place: location.place,
+        ..query
+    };
+    let lang = query.lang;
+    let cache_key = cache_key(state, &location.id, &query);
+
+    match state.cache.lookup(&cache_key).await {
+        Freshness::Fresh { entry, age } => {
+            return Ok(Cached {
+                entry,
+                status: "fresh",
+                age,
+                lang,
+            });
+        }
+        Freshness::Stale { entry, age } => {
+            // answer right away, the next request gets the refreshed entry.
+            // out of budget the stale entry is all there is
+            if !state.budget.degraded().await {
+                tokio::spawn(refresh(state.clone(), cache_key, query));
+            }
+            return Ok(Cached {
+                entry,
+                status: "stale",
+                age,
+                lang,
+            });
+        }
+        Freshness::Missing => {}
+    }
+
+    let entry = state
+        .inflight
+        .run(cache_key.clone(), || {
+            fetch_and_store(state, cache_key, &query)
+        })
+        .await?;
+    Ok(Cached {
+        entry,
+        status: "miss",
+        age: 0,
+        lang,
+    })
+}
+
+fn cache_key(state: &AppState, location: &str, query: &WeatherQuery) -> CacheKey {
+    CacheKey {
+        location: location.to_string(),
+        api_type: query.api_type.clone(),
+        // one metric entry per city, other units are converted on the way out
+        units: WeatherUnits::Metric,
+        lang: query.lang,
+        bucket_ts: state
+            .buckets
+            .bucket_for(location, &query.api_type, now_ts()),
+    }
+}
+
+async fn refresh(state: AppState, cache_key: CacheKey, query: WeatherQuery) {
+    let refreshed = state
+        .inflight
+        .run(cache_key.clone(), || {
+            fetch_and_store(&state, cache_key, &query)
+        })
+        .await;
+    if let Err(e) = refreshed {
+        eprintln!("background refresh for {} failed: {}", query.place, e);
+    }
+}
+
+async fn fetch_and_store(
+    state: &AppState,
+    cache_key: CacheKey,
+    query: &WeatherQuery,
+) -> FetchResult {
+    if state.budget.degraded().await {
+        return Err(WeatherError::BudgetExhausted);
+    }
+    let report = state
+        .provider
+        .fetch(query)
+        .await
+        .map_err(WeatherError::from_upstream)?;
+    state.budget.charge(report.cost).await;
+
+    // the first response for a city teaches us its canonical id and timezone,
+    // rekey with both so every spelling lands on the same entry
+    let location = match &query.place {
+        Place::City(name) => state.locations.learn(name, &report.location).await,
+        // a geohash cell already is canonical
+        Place::Coords { .. } => cache_key.location.clone(),
+    };
+    state
+        .buckets
+        .learn_offset(&location, report.location.tzoffset);
+    let cache_key = CacheKey {
+        bucket_ts: state
+            .buckets
+            .bucket_for(&location, &cache_key.api_type, now_ts()),
+        location: location.clone(),
+        ..cache_key
+    };
+
+    if let (Some(current), Some(prepared)) = (&report.current, report.prepared_temp(now_ts())) {
+        let record = Record {
+            location_id: &location,
+            query_name: &query.place.to_string(),
+            location_name: &report.location.name,
+            observed_at: current.observed_at,
+        };
+        if let Err(e) = state.storage.record(record, &prepared).await {
+            eprintln!("recording history for {} failed: {}", query.place, e);
+        }
+    }
+
+    let entry = CacheEntry::new(report, state.config.ttl_for(&query.api_type));
+
+    state.cache.set(cache_key, entry.clone()).await;
+
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Days;
+
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn parse_range_accepts_past_ranges() {
+        assert_eq!(
+            parse_range("2024-01-01", "2024-01-31"),
+            Ok((date("2024-01-01"), date("2024-01-31")))
+        );
+        assert!(parse_range("1970-01-01", "1970-01-01").is_ok());
+    }
+
+    #[test]
+    fn parse_range_rejects_bad_ranges() {
+        assert!(parse_range("2024-01-xx", "2024-01-02").is_err());
+        assert!(parse_range("2024-02-30", "2024-03-01").is_err());
+        assert!(parse_range("2024-01-02", "2024-01-01").is_err());
+        assert!(parse_range("1969-12-31", "1970-01-01").is_err());
+    }
+
+    #[test]
+    fn parse_range_ends_by_yesterday() {
+        let today = Utc::now().date_naive();
+        let yesterday = today - Days::new(1);
+        assert!(parse_range(&yesterday.to_string(), &yesterday.to_string()).is_ok());
+        assert!(parse_range(&yesterday.to_string(), &today.to_string()).is_err());
+    }
+
+    #[test]
+    fn parse_range_limits_the_days() {
+        assert!(parse_range("2024-01-01", "2024-01-31").is_ok());
+        assert_eq!(
+            parse_range("2024-01-01", "2024-02-01"),
+            Err(format!("at most {} days per query", MAX_HISTORICAL_DAYS))
+        );
+    }
+
+    #[test]
+    fn contiguous_runs_split_at_gaps() {
+        let dates = [
+            "2024-01-01",
+            "2024-01-02",
+            "2024-01-04",
+            "2024-01-06",
+            "2024-01-07",
+        ]
+        .map(date);
+        assert_eq!(
+            contiguous_runs(&dates),
+            vec![
+                (date("2024-01-01"), date("2024-01-02")),
+                (date("2024-01-04"), date("2024-01-04")),
+                (date("2024-01-06"), date("2024-01-07")),
+            ]
+        );
+        assert!(contiguous_runs(&[]).is_empty());
+    }
+
+    #[test]
+    fn day_is_over_at_local_midnight() {
+        // 2024-01-02 00:00 utc
+        let midnight = 1_704_153_600;
+        let new_year = date("2024-01-01");
+        assert!(!day_is_over(new_year, 0.0, midnight - 1));
+        assert!(day_is_over(new_year, 0.0, midnight));
+        // at +3 the day ended three hours earlier, at -5 it runs five hours longer
+        assert!(day_is_over(new_year, 3.0, midnight - 3 * 3600));
+        assert!(!day_is_over(new_year, -5.0, midnight + 5 * 3600 - 1));
+        assert!(day_is_over(new_year, -5.0, midnight + 5 * 3600));
+    }
 }
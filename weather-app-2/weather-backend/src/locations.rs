@@ -0,0 +1,272 @@
+use std::num::NonZeroUsize;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use lru::LruCache;
+use redis::AsyncCommands;
+use redis::aio::MultiplexedConnection;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::models::Place;
+use crate::models::domain::Location;
+use crate::storage::Storage;
+
+// names resolved recently are kept in memory, older ones are read back from the store
+const REMEMBERED_NAMES: usize = 100_000;
+
+// spellings upstreams resolve poorly or inconsistently, mapped to the name we query with
+const ALIASES: &[(&str, &str)] = &[
+    ("msk", "moscow"),
+    ("москва", "moscow"),
+    ("spb", "saint petersburg"),
+    ("st petersburg", "saint petersburg"),
+    ("st. petersburg", "saint petersburg"),
+    ("питер", "saint petersburg"),
+    ("санкт-петербург", "saint petersburg"),
+    ("nyc", "new york"),
+];
+
+#[derive(Debug, Clone)]
+pub struct ResolvedLocation {
+    // what the cache is keyed by
+    pub id: String,
+    // what the upstream is asked for
+    pub place: Place,
+    // hours from utc, once an upstream told us
+    pub tzoffset: Option<f32>,
+}
+
+// what an upstream answered for a name
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnownLocation {
+    pub id: String,
+    pub tzoffset: f32,
+}
+
+// shared by every instance and kept over restarts, so a name learned once
+// finds the entries cached under its canonical id everywhere
+#[async_trait]
+pub trait NameStore: Send + Sync {
+    async fn get(&self, name: &str) -> Result<Option<KnownLocation>>;
+    async fn set(&self, name: &str, location: &KnownLocation) -> Result<()>;
+}
+
+// maps whatever the user typed to one canonical location id, so every
+// spelling of a place shares one cache entry and one upstream call
+pub struct Locations {
+    // normalized names to the ids learned from upstream responses
+    known: Mutex<LruCache<String, KnownLocation>>,
+    store: Box<dyn NameStore>,
+    geohash_precision: usize,
+}
+
+impl Locations {
+    pub async fn from_config(config: &Config, storage: Storage) -> Result<Locations> {
+        let store: Box<dyn NameStore> = match config.uses_redis() {
+            true => Box::new(RedisNameStore::new(&config.redis_url).await?),
+            false => Box::new(storage),
+        };
+        Ok(Locations::new(store, config.geohash_precision))
+    }
+
+    pub fn new(store: Box<dyn NameStore>, geohash_precision: usize) -> Locations {
+        Locations {
+            known: Mutex::new(LruCache::new(NonZeroUsize::new(REMEMBERED_NAMES).unwrap())),
+            store,
+            geohash_precision,
+        }
+    }
+
+    pub async fn resolve(&self, place: &Place) -> Result<ResolvedLocation> {
+        match place {
+            Place::City(name) => Ok(self.resolve_name(name).await),
+            Place::Coords {
+                latitude,
+                longitude,
+            } => self.resolve_coords(*latitude, *longitude),
+        }
+    }
+
+    async fn resolve_name(&self, raw: &str) -> ResolvedLocation {
+        let name = normalize(raw);
+        let name = ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == name)
+            .map(|(_, target)| target.to_string())
+            .unwrap_or(name);
+        let known = self.known(&name).await;
+        ResolvedLocation {
+            // until an upstream answered for this name the name itself is the id
+            id: known
+                .as_ref()
+                .map(|known| known.id.clone())
+                .unwrap_or_else(|| format!("name:{}", name)),
+            tzoffset: known.map(|known| known.tzoffset),
+            place: Place::City(name),
+        }
+    }
+
+    async fn known(&self, name: &str) -> Option<KnownLocation> {
+        if let Some(known) = self.known.lock().await.get(name) {
+            return Some(known.clone());
+        }
+        // another instance, or this one before a restart, may have learned it
+        let known = self.store.get(name).await.unwrap_or_else(|e| {
+            eprintln!("reading the location of '{}' failed: {}", name, e);
+            None
+        })?;
+        self.known.lock().await.put(name.to_string(), known.clone());
+        Some(known)
+    }
+
+    // every point in a geohash cell shares the entry fetched for the cell centre
+    fn resolve_coords(&self, latitude: f64, longitude: f64) -> Result<ResolvedLocation> {
+        let cell = geohash::encode(
+            geohash::Coord {
+                x: longitude,
+                y: latitude,
+            },
+            self.geohash_precision,
+        )?;
+        let (centre, _, _) = geohash::decode(&cell)?;
+        Ok(ResolvedLocation {
+            id: format!("geo:{}", cell),
+            place: Place::Coords {
+                latitude: centre.y,
+                longitude: centre.x,
+            },
+            tzoffset: None,
+        })
+    }
+
+    // remembers both the queried name and the upstream's own name for the place
+    pub async fn learn(&self, name: &str, location: &Location) -> String {
+        let known = KnownLocation {
+            id: location_id(location.latitude, location.longitude),
+            tzoffset: location.tzoffset,
+        };
+        for name in [name.to_string(), normalize(&location.name)] {
+            let unchanged = self.known.lock().await.get(&name) == Some(&known);
+            if unchanged {
+                continue;
+            }
+            if let Err(e) = self.store.set(&name, &known).await {
+                eprintln!("storing the location of '{}' failed: {}", name, e);
+            }
+            self.known.lock().await.put(name, known.clone());
+        }
+        known.id
+    }
+}
+
+pub fn normalize(raw: &str) -> String {
+    raw.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+// two decimals is roughly a kilometre, closer than upstreams agree on a city centre
+fn location_id(latitude: f32, longitude: f32) -> String {
+    format!("{:.2},{:.2}", latitude, longitude)
+}
+
+#[async_trait]
+impl NameStore for Storage {
+    async fn get(&self, name: &str) -> Result<Option<KnownLocation>> {
+        self.location(name).await
+    }
+    async fn set(&self, name: &str, location: &KnownLocation) -> Result<()> {
+        self.add_location(name, location).await
+    }
+}
+
+pub struct RedisNameStore {
+    con: MultiplexedConnection,
+}
+
+impl RedisNameStore {
+    const PREFIX: &str = "weather:location";
+
+    pub async fn new(url: &str) -> Result<RedisNameStore> {
+        let client = redis::Client::open(url)?;
+        let con = client.get_multiplexed_tokio_connection().await?;
+        Ok(RedisNameStore { con })
+    }
+
+    fn key(name: &str) -> String {
+        format!("{}:{}", Self::PREFIX, name)
+    }
+}
+
+#[async_trait]
+impl NameStore for RedisNameStore {
+    async fn get(&self, name: &str) -> Result<Option<KnownLocation>> {
+        let mut con = self.con.clone();
+        let raw: Option<String> = con.get(Self::key(name)).await?;
+        match raw {
+            Some(raw) => Ok(Some(serde_json::from_str(&raw)?)),
+            None => Ok(None),
+        }
+    }
+    async fn set(&self, name: &str, location: &KnownLocation) -> Result<()> {
+        let mut con = self.con.clone();
+        let raw = serde_json::to_string(location)?;
+        let _: () = con.set(Self::key(name), raw).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moscow() -> Location {
+        Location {
+            name: "Moscow, Russia".to_string(),
+            latitude: 55.7558,
+            longitude: 37.6173,
+            tzoffset: 3.0,
+        }
+    }
+
+    fn locations(storage: &Storage) -> Locations {
+        Locations::new(Box::new(storage.clone()), 5)
+    }
+
+    async fn id(locations: &Locations, name: &str) -> String {
+        locations
+            .resolve(&Place::City(name.to_string()))
+            .await
+            .unwrap()
+            .id
+    }
+
+    #[tokio::test]
+    async fn spellings_of_a_city_share_one_id() {
+        let storage = Storage::open(":memory:").unwrap();
+        let locations = locations(&storage);
+        for name in ["Moscow", "moscow ", "Москва"] {
+            assert_eq!(id(&locations, name).await, "name:moscow");
+        }
+        assert_eq!(locations.learn("moscow", &moscow()).await, "55.76,37.62");
+        for name in ["Moscow", "moscow ", "Москва", "moscow,  russia"] {
+            assert_eq!(id(&locations, name).await, "55.76,37.62");
+        }
+    }
+
+    #[tokio::test]
+    async fn learned_names_outlive_the_instance() {
+        let storage = Storage::open(":memory:").unwrap();
+        locations(&storage).learn("moscow", &moscow()).await;
+        let restarted = locations(&storage);
+        let resolved = restarted
+            .resolve(&Place::City("Москва".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(resolved.id, "55.76,37.62");
+        assert_eq!(resolved.tzoffset, Some(3.0));
+    }
+}
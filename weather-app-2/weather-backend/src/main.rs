@@ -1,36 +1,99 @@
 mod api;
+mod buckets;
+mod budget;
 mod cache;
+mod config;
+mod error;
+mod extract;
+mod failover;
 mod handlers;
+mod locations;
+mod metrics;
 mod models;
+mod ratelimit;
+mod singleflight;
+mod state;
 mod storage;
 
-use std::sync::{Arc, RwLock};
+use std::net::SocketAddr;
 
 use anyhow::Result;
 use axum::{
-    Router,
+    Router, middleware,
     routing::{get, post},
 };
+use clap::Parser;
 use tokio::net::TcpListener;
 use tower_http::services::ServeDir;
 
-use crate::cache::{CacheService, RuntimeCache};
+use crate::budget::Budget;
+use crate::cache::CacheService;
+use crate::config::{Args, Config};
+use crate::locations::Locations;
+use crate::ratelimit::RateLimiter;
+use crate::state::AppState;
+use crate::storage::Storage;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    dotenvy::dotenv()?;
+    let args = Args::parse();
+    // a .env is a local convenience, deployments set real env vars
+    if let Err(e) = dotenvy::dotenv()
+        && !e.not_found()
+    {
+        return Err(e.into());
+    }
 
-    let cache = Arc::new(RwLock::new(CacheService::new(RuntimeCache::new())));
+    let config = Config::load(&args)?;
+    if args.print_config {
+        println!("{}", config);
+        return Ok(());
+    }
+    println!("effective config:\n{}", config);
+
+    let provider = api::provider_from_config(&config)?;
+    let cache = CacheService::from_config(&config).await?;
+    let storage = Storage::open(&config.history_db)?;
+    let limiter = RateLimiter::from_config(&config).await?;
+    let budget = Budget::load(&config, storage.clone()).await?;
+    let locations = Locations::from_config(&config, storage.clone()).await?;
+    let bind_addr = config.bind_addr;
+    let state = AppState::new(config, provider, cache, storage, limiter, budget, locations);
+    metrics::spawn_entries_counter(&state.cache, state.config.sweep_interval);
+
+    // everything that can reach a paid upstream is limited per client
+    let upstream = Router::new()
+        .route("/api/weather", post(handlers::get_current_temperature))
+        .route("/api/forecast", get(handlers::get_forecast))
+        .route("/api/forecast/hourly", get(handlers::get_hourly_forecast))
+        .route("/api/alerts", get(handlers::get_alerts))
+        .route(
+            "/api/historical/{city}/{start}/{end}",
+            get(handlers::get_historical),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            ratelimit::limit,
+        ));
 
     let router = Router::new()
         .route("/", get(handlers::get_homepage))
-        .route("/api/weather", post(handlers::get_current_temperature))
+        .merge(upstream)
+        .route("/api/history", get(handlers::get_history))
+        .route("/api/budget", get(handlers::get_budget))
+        .route("/metrics", get(handlers::get_metrics))
         .nest_service("/static", ServeDir::new("static"))
-        .with_state(cache);
+        .layer(middleware::from_fn(metrics::track))
+        .with_state(state);
 
-    let listener = TcpListener::bind("0.0.0.0:3000").await?;
+    let listener = TcpListener::bind(bind_addr).await?;
 
-    axum::serve(listener, router).await?;
+    // the rate limiter keys anonymous clients by their address
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -0,0 +1,175 @@
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    CounterVec, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tokio::time::MissedTickBehavior;
+
+use crate::cache::{Cache, CacheService, CacheStats, Freshness};
+
+// everything is registered once, on first use, and scraped from /metrics
+struct Metrics {
+    registry: Registry,
+    cache_lookups: IntCounterVec,
+    cache_evictions: IntCounterVec,
+    cache_entries: IntGaugeVec,
+    upstream_duration: HistogramVec,
+    upstream_cost: CounterVec,
+    request_duration: HistogramVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new();
+    let cache_lookups = IntCounterVec::new(
+        Opts::new(
+            "weather_cache_lookups_total",
+            "cache lookups by result: hit, stale_hit, aprx_hit or miss",
+        ),
+        &["backend", "result"],
+    )
+    .unwrap();
+    let cache_evictions = IntCounterVec::new(
+        Opts::new(
+            "weather_cache_evictions_total",
+            "entries dropped for capacity or because they expired",
+        ),
+        &["backend", "reason"],
+    )
+    .unwrap();
+    let cache_entries = IntGaugeVec::new(
+        Opts::new(
+            "weather_cache_entries",
+            "entries cached, counted every sweep interval",
+        ),
+        &["backend"],
+    )
+    .unwrap();
+    let upstream_duration = HistogramVec::new(
+        HistogramOpts::new(
+            "weather_upstream_request_duration_seconds",
+            "upstream calls by provider and outcome, ok or an error code",
+        ),
+        &["provider", "status"],
+    )
+    .unwrap();
+    let upstream_cost = CounterVec::new(
+        Opts::new(
+            "weather_upstream_query_cost_total",
+            "query cost billed by each provider",
+        ),
+        &["provider"],
+    )
+    .unwrap();
+    let request_duration = HistogramVec::new(
+        HistogramOpts::new(
+            "weather_http_request_duration_seconds",
+            "requests served by route",
+        ),
+        &["method", "route", "status"],
+    )
+    .unwrap();
+    for collector in [
+        Box::new(cache_lookups.clone()) as Box<dyn prometheus::core::Collector>,
+        Box::new(cache_evictions.clone()),
+        Box::new(cache_entries.clone()),
+        Box::new(upstream_duration.clone()),
+        Box::new(upstream_cost.clone()),
+        Box::new(request_duration.clone()),
+    ] {
+        registry.register(collector).unwrap();
+    }
+    Metrics {
+        registry,
+        cache_lookups,
+        cache_evictions,
+        cache_entries,
+        upstream_duration,
+        upstream_cost,
+        request_duration,
+    }
+});
+
+pub fn record_lookup(backend: &str, freshness: &Freshness, approximate: bool) {
+    let result = match freshness {
+        Freshness::Missing => "miss",
+        _ if approximate => "aprx_hit",
+        Freshness::Fresh { .. } => "hit",
+        Freshness::Stale { .. } => "stale_hit",
+    };
+    METRICS
+        .cache_lookups
+        .with_label_values(&[backend, result])
+        .inc();
+}
+
+// backends count their own evictions, the counters are caught up on each scrape
+pub fn record_cache(backend: &str, stats: CacheStats) {
+    for (reason, total) in [("capacity", stats.evicted), ("expired", stats.expired)] {
+        let counter = METRICS
+            .cache_evictions
+            .with_label_values(&[backend, reason]);
+        counter.inc_by(total.saturating_sub(counter.get()));
+    }
+}
+
+// counting redis entries means a SCAN, too much to do on every scrape
+pub fn spawn_entries_counter(cache: &Arc<CacheService>, every: Duration) {
+    let cache = Arc::downgrade(cache);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(cache) = cache.upgrade() else {
+                break;
+            };
+            METRICS
+                .cache_entries
+                .with_label_values(&[cache.name()])
+                .set(cache.len().await as i64);
+        }
+    });
+}
+
+pub fn record_upstream(provider: &str, status: &str, elapsed: Duration) {
+    METRICS
+        .upstream_duration
+        .with_label_values(&[provider, status])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn record_cost(provider: &str, cost: f32) {
+    METRICS
+        .upstream_cost
+        .with_label_values(&[provider])
+        .inc_by(cost.max(0.0) as f64);
+}
+
+// labelled by the route template, not the path, so cities don't blow up cardinality
+pub async fn track(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let started = Instant::now();
+    let response = next.run(request).await;
+    METRICS
+        .request_duration
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+pub fn render() -> Result<String> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -1,64 +1,263 @@
+use std::fmt;
+
+use chrono::NaiveDate;
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+// the language can only be picked in the query string, the body is the form
+#[derive(Debug, Clone, Deserialize)]
+pub struct LangParams {
+    pub lang: Option<String>,
+}
+
+// either a city or gps coordinates
+#[derive(Debug, Clone, Deserialize)]
 pub struct FormCity {
+    pub city: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    #[serde(default)]
+    pub units: WeatherUnits,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForecastParams {
+    pub city: String,
+    pub days: Option<usize>,
+    #[serde(default)]
+    pub units: WeatherUnits,
+    pub lang: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertParams {
+    pub city: String,
+    // only alerts in effect right now unless false
+    pub active: Option<bool>,
+    // also filters the alerts by their language
+    pub lang: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryParams {
     pub city: String,
-    pub timestamp: u64,
+    // unix seconds, the last week when absent
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    #[serde(default)]
+    pub units: WeatherUnits,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoricalParams {
+    #[serde(default)]
+    pub units: WeatherUnits,
+    pub lang: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HourlyParams {
+    pub city: String,
+    pub hours: Option<usize>,
+    // comma separated, every field when absent
+    pub fields: Option<String>,
+    #[serde(default)]
+    pub units: WeatherUnits,
+    pub lang: Option<String>,
+}
+
+impl FormCity {
+    pub fn place(&self) -> Result<Place, String> {
+        match (&self.city, self.lat, self.lon) {
+            (None, Some(latitude), Some(longitude)) => {
+                if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                    return Err("lat must be within ±90 and lon within ±180".to_string());
+                }
+                Ok(Place::Coords {
+                    latitude,
+                    longitude,
+                })
+            }
+            (Some(city), None, None) => Ok(Place::City(city.clone())),
+            _ => Err("expected either city or both lat and lon".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Place {
+    City(String),
+    Coords { latitude: f64, longitude: f64 },
+}
+impl fmt::Display for Place {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Place::City(name) => write!(f, "{}", name),
+            Place::Coords {
+                latitude,
+                longitude,
+            } => write!(f, "{},{}", latitude, longitude),
+        }
+    }
+}
+
+// what a provider is asked to fetch
+#[derive(Debug, Clone)]
+pub struct WeatherQuery {
+    pub place: Place,
+    pub api_type: WeatherApiType,
+    pub lang: WeatherLang,
+    // inclusive, only for historical queries
+    pub dates: Option<(NaiveDate, NaiveDate)>,
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct CacheKey {
-    pub city: String,
+    // canonical location id, see locations.rs
+    pub location: String,
     pub api_type: WeatherApiType,
     pub units: WeatherUnits,
     pub lang: WeatherLang,
     pub bucket_ts: u64,
 }
-impl ToString for CacheKey {
-    fn to_string(&self) -> String {
-        format!(
+impl CacheKey {
+    // None when the keys belong to different series and are not comparable
+    pub fn bucket_distance(&self, other: &CacheKey) -> Option<u64> {
+        let same_series = self.location == other.location
+            && self.api_type == other.api_type
+            && self.units == other.units
+            && self.lang == other.lang;
+        same_series.then(|| self.bucket_ts.abs_diff(other.bucket_ts))
+    }
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "{}-{}-{}-{}-{}",
-            self.city,
-            self.api_type.to_string(),
-            self.units.to_string(),
-            self.lang.to_string(),
-            self.bucket_ts
+            self.location, self.api_type, self.units, self.lang, self.bucket_ts
         )
     }
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
 pub enum WeatherLang {
+    #[default]
     En,
     Ru,
 }
-impl ToString for WeatherLang {
-    fn to_string(&self) -> String {
+impl fmt::Display for WeatherLang {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            WeatherLang::En => "en".to_string(),
-            WeatherLang::Ru => "ru".to_string(),
+            WeatherLang::En => write!(f, "en"),
+            WeatherLang::Ru => write!(f, "ru"),
         }
     }
 }
+impl WeatherLang {
+    // "ru", "ru-RU" and "RU" all map to Ru
+    pub fn from_tag(tag: &str) -> Option<WeatherLang> {
+        let primary = tag.trim().split(['-', '_']).next()?;
+        match primary.to_lowercase().as_str() {
+            "en" => Some(WeatherLang::En),
+            "ru" => Some(WeatherLang::Ru),
+            _ => None,
+        }
+    }
+
+    // an explicit choice wins, then the most preferred supported Accept-Language entry
+    pub fn negotiate(param: Option<&str>, accept_language: Option<&str>) -> WeatherLang {
+        if let Some(lang) = param.and_then(WeatherLang::from_tag) {
+            return lang;
+        }
+        let mut best: Option<(WeatherLang, f32)> = None;
+        for range in accept_language.unwrap_or_default().split(',') {
+            let mut parts = range.split(';');
+            let Some(lang) = parts.next().and_then(WeatherLang::from_tag) else {
+                continue;
+            };
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((lang, quality));
+            }
+        }
+        best.map(|(lang, _)| lang).unwrap_or_default()
+    }
+}
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub enum WeatherApiType {
     Current,
+    Forecast,
+    Historical,
 }
-impl ToString for WeatherApiType {
-    fn to_string(&self) -> String {
+impl fmt::Display for WeatherApiType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            WeatherApiType::Current => "current".to_string(),
+            WeatherApiType::Current => write!(f, "current"),
+            WeatherApiType::Forecast => write!(f, "forecast"),
+            WeatherApiType::Historical => write!(f, "historical"),
         }
     }
 }
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+impl WeatherApiType {
+    // a neighbouring bucket is a fine stand-in for current weather, never for another date
+    pub fn approximable(&self) -> bool {
+        !matches!(self, WeatherApiType::Historical)
+    }
+}
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Deserialize, Default, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum WeatherUnits {
+    // celsius, km/h, hPa, mm
+    #[default]
     Metric,
+    // fahrenheit, mph, inHg, inches
+    Us,
+    // celsius, mph, hPa, mm
+    Uk,
+    // kelvin, m/s, hPa, mm
+    Base,
 }
-impl ToString for WeatherUnits {
-    fn to_string(&self) -> String {
+impl fmt::Display for WeatherUnits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WeatherUnits::Metric => write!(f, "metric"),
+            WeatherUnits::Us => write!(f, "us"),
+            WeatherUnits::Uk => write!(f, "uk"),
+            WeatherUnits::Base => write!(f, "base"),
+        }
+    }
+}
+
+// everything is fetched and cached in metric, other units are derived from it
+impl WeatherUnits {
+    pub fn temp(&self, celsius: f32) -> f32 {
+        match self {
+            WeatherUnits::Metric | WeatherUnits::Uk => celsius,
+            WeatherUnits::Us => celsius * 9.0 / 5.0 + 32.0,
+            WeatherUnits::Base => celsius + 273.15,
+        }
+    }
+    pub fn speed(&self, kmh: f32) -> f32 {
+        match self {
+            WeatherUnits::Metric => kmh,
+            WeatherUnits::Us | WeatherUnits::Uk => kmh / 1.609_344,
+            WeatherUnits::Base => kmh / 3.6,
+        }
+    }
+    pub fn pressure(&self, hpa: f32) -> f32 {
+        match self {
+            WeatherUnits::Us => hpa / 33.863_89,
+            _ => hpa,
+        }
+    }
+    pub fn precip(&self, mm: f32) -> f32 {
         match self {
-            WeatherUnits::Metric => "metric".to_string(),
+            WeatherUnits::Us => mm / 25.4,
+            _ => mm,
         }
     }
 }
@@ -70,9 +269,14 @@ impl ToString for WeatherUnits {
 // }
 
 pub mod api {
-    use serde::Serialize;
+    use std::str::FromStr;
 
-    #[derive(Debug, Clone, Serialize)]
+    use anyhow::{Result, anyhow};
+    use serde::{Deserialize, Serialize};
+
+    use super::WeatherUnits;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct PreparedTemp {
         pub temp: f32,
         pub temp_max: f32,
@@ -80,6 +284,13 @@ pub mod api {
         pub humidity: f32,
         pub pressure: f32,
         pub wind_speed: f32,
+        // an alert is in effect, details are at /api/alerts
+        #[serde(default)]
+        pub alerts: bool,
+        #[serde(default)]
+        pub conditions: String,
+        #[serde(default)]
+        pub description: Option<String>,
     }
 
     impl PreparedTemp {
@@ -90,6 +301,7 @@ pub mod api {
             humidity: f32,
             pressure: f32,
             wind_speed: f32,
+            alerts: bool,
         ) -> Self {
             Self {
                 temp,
@@ -98,24 +310,468 @@ pub mod api {
                 humidity,
                 pressure,
                 wind_speed,
+                alerts,
+                conditions: String::new(),
+                description: None,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct BudgetStatus {
+        // utc day the spend is for
+        pub date: String,
+        pub spent: f64,
+        // the rest are null without a configured budget
+        pub budget: Option<f64>,
+        pub remaining: Option<f64>,
+        // spend at which upstream calls stop
+        pub degrade_at: Option<f64>,
+        pub degraded: bool,
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct History {
+        pub location: String,
+        pub observations: Vec<Observation>,
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Observation {
+        pub location: String,
+        pub observed_at: u64,
+        pub temp: f32,
+        pub temp_max: f32,
+        pub temp_min: f32,
+        pub humidity: f32,
+        pub pressure: f32,
+        pub wind_speed: f32,
+        pub conditions: String,
+    }
+
+    impl Observation {
+        // stored metric like the cache
+        pub fn in_units(self, units: WeatherUnits) -> Observation {
+            Observation {
+                temp: units.temp(self.temp),
+                temp_max: units.temp(self.temp_max),
+                temp_min: units.temp(self.temp_min),
+                pressure: units.pressure(self.pressure),
+                wind_speed: units.speed(self.wind_speed),
+                ..self
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Alerts {
+        pub location: String,
+        pub alerts: Vec<Alert>,
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Alert {
+        pub id: String,
+        pub event: String,
+        pub headline: String,
+        pub description: String,
+        pub language: String,
+        pub link: String,
+        pub onset: u64,
+        pub ends: Option<u64>,
+        pub active: bool,
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Forecast {
+        pub location: String,
+        pub description: Option<String>,
+        pub days: Vec<ForecastDay>,
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct ForecastDay {
+        pub date: String,
+        pub temp: f32,
+        pub temp_max: f32,
+        pub temp_min: f32,
+        pub conditions: String,
+        pub icon: String,
+        pub precip: f32,
+        pub precip_prob: f32,
+        pub wind_speed: f32,
+    }
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct HourlyForecast {
+        pub location: String,
+        pub hours: Vec<ForecastHour>,
+    }
+
+    // unselected fields are left out of the payload
+    #[derive(Debug, Clone, Default, Serialize)]
+    pub struct ForecastHour {
+        pub time: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub temp: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub humidity: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub pressure: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub wind_speed: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub precip: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub precip_prob: Option<f32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub conditions: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub icon: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum HourField {
+        Temp,
+        Humidity,
+        Pressure,
+        WindSpeed,
+        Precip,
+        PrecipProb,
+        Conditions,
+        Icon,
+    }
+
+    impl HourField {
+        pub const ALL: [HourField; 8] = [
+            HourField::Temp,
+            HourField::Humidity,
+            HourField::Pressure,
+            HourField::WindSpeed,
+            HourField::Precip,
+            HourField::PrecipProb,
+            HourField::Conditions,
+            HourField::Icon,
+        ];
+    }
+
+    impl FromStr for HourField {
+        type Err = anyhow::Error;
+
+        // upstream spellings are accepted next to ours
+        fn from_str(s: &str) -> Result<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "temp" => Ok(HourField::Temp),
+                "humidity" => Ok(HourField::Humidity),
+                "pressure" => Ok(HourField::Pressure),
+                "wind_speed" | "windspeed" => Ok(HourField::WindSpeed),
+                "precip" => Ok(HourField::Precip),
+                "precip_prob" | "precipprob" => Ok(HourField::PrecipProb),
+                "conditions" => Ok(HourField::Conditions),
+                "icon" => Ok(HourField::Icon),
+                other => Err(anyhow!("unknown hourly field '{}'", other)),
+            }
+        }
+    }
+}
+
+// provider-neutral weather, every upstream adapter maps into this.
+// temperatures are in celsius, wind speed in km/h, pressure in hPa
+pub mod domain {
+    use serde::{Deserialize, Serialize};
+
+    use super::{WeatherLang, WeatherUnits, api};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct WeatherReport {
+        pub location: Location,
+        pub current: Option<Conditions>,
+        // starts with the local today
+        pub days: Vec<Day>,
+        // only filled for forecasts, older cached reports have none
+        #[serde(default)]
+        pub hours: Vec<Hour>,
+        #[serde(default)]
+        pub alerts: Vec<Alert>,
+        // upstream summary of the coming days, in the requested language
+        #[serde(default)]
+        pub description: Option<String>,
+        // upstream billing units spent on this report
+        pub cost: f32,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Location {
+        pub name: String,
+        pub latitude: f32,
+        pub longitude: f32,
+        // hours from utc
+        pub tzoffset: f32,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Conditions {
+        pub observed_at: u64,
+        pub temp: f32,
+        pub humidity: f32,
+        pub pressure: f32,
+        pub wind_speed: f32,
+        pub conditions: String,
+        pub icon: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Day {
+        // local date, yyyy-mm-dd
+        pub date: String,
+        pub temp: f32,
+        pub temp_max: f32,
+        pub temp_min: f32,
+        pub conditions: String,
+        pub icon: String,
+        // mm
+        pub precip: f32,
+        // percent
+        pub precip_prob: f32,
+        pub wind_speed: f32,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Hour {
+        // start of the hour, unix seconds
+        pub time: u64,
+        pub temp: f32,
+        pub humidity: f32,
+        pub pressure: f32,
+        pub wind_speed: f32,
+        pub precip: f32,
+        pub precip_prob: f32,
+        pub conditions: String,
+        pub icon: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Alert {
+        pub id: String,
+        pub event: String,
+        pub headline: String,
+        pub description: String,
+        pub language: String,
+        pub link: String,
+        pub onset: u64,
+        // open ended when absent
+        pub ends: Option<u64>,
+    }
+
+    impl Alert {
+        pub fn is_active(&self, now: u64) -> bool {
+            self.onset <= now && self.ends.is_none_or(|ends| now < ends)
+        }
+
+        fn to_api(&self, now: u64) -> api::Alert {
+            api::Alert {
+                id: self.id.clone(),
+                event: self.event.clone(),
+                headline: self.headline.clone(),
+                description: self.description.clone(),
+                language: self.language.clone(),
+                link: self.link.clone(),
+                onset: self.onset,
+                ends: self.ends,
+                active: self.is_active(now),
+            }
+        }
+    }
+
+    impl WeatherReport {
+        pub fn in_units(&self, units: WeatherUnits) -> WeatherReport {
+            if units == WeatherUnits::Metric {
+                return self.clone();
+            }
+            WeatherReport {
+                location: self.location.clone(),
+                current: self.current.as_ref().map(|c| Conditions {
+                    temp: units.temp(c.temp),
+                    pressure: units.pressure(c.pressure),
+                    wind_speed: units.speed(c.wind_speed),
+                    ..c.clone()
+                }),
+                days: self
+                    .days
+                    .iter()
+                    .map(|d| Day {
+                        temp: units.temp(d.temp),
+                        temp_max: units.temp(d.temp_max),
+                        temp_min: units.temp(d.temp_min),
+                        precip: units.precip(d.precip),
+                        wind_speed: units.speed(d.wind_speed),
+                        ..d.clone()
+                    })
+                    .collect(),
+                hours: self
+                    .hours
+                    .iter()
+                    .map(|h| Hour {
+                        temp: units.temp(h.temp),
+                        pressure: units.pressure(h.pressure),
+                        wind_speed: units.speed(h.wind_speed),
+                        precip: units.precip(h.precip),
+                        ..h.clone()
+                    })
+                    .collect(),
+                alerts: self.alerts.clone(),
+                description: self.description.clone(),
+                cost: self.cost,
+            }
+        }
+
+        pub fn prepared_temp(&self, now: u64) -> Option<api::PreparedTemp> {
+            let current = self.current.as_ref()?;
+            let (temp_max, temp_min) = self
+                .days
+                .first()
+                .map(|today| (today.temp_max, today.temp_min))
+                .unwrap_or((current.temp, current.temp));
+            Some(api::PreparedTemp {
+                conditions: current.conditions.clone(),
+                description: self.description.clone(),
+                ..api::PreparedTemp::new(
+                    current.temp,
+                    temp_max,
+                    temp_min,
+                    current.humidity,
+                    current.pressure,
+                    current.wind_speed,
+                    self.alerts.iter().any(|alert| alert.is_active(now)),
+                )
+            })
+        }
+
+        // `lang` keeps only alerts whose tag has that primary subtag, "ru-RU" is ru
+        pub fn alerts(
+            &self,
+            now: u64,
+            active_only: bool,
+            lang: Option<WeatherLang>,
+        ) -> api::Alerts {
+            api::Alerts {
+                location: self.location.name.clone(),
+                alerts: self
+                    .alerts
+                    .iter()
+                    .filter(|alert| !active_only || alert.is_active(now))
+                    .filter(|alert| {
+                        lang.is_none_or(|lang| WeatherLang::from_tag(&alert.language) == Some(lang))
+                    })
+                    .map(|alert| alert.to_api(now))
+                    .collect(),
+            }
+        }
+
+        pub fn forecast(&self, days: usize) -> api::Forecast {
+            api::Forecast {
+                location: self.location.name.clone(),
+                description: self.description.clone(),
+                days: self.days.iter().take(days).map(Day::to_api).collect(),
+            }
+        }
+
+        // the slots covering the next `hours` hours, starting with the one `now`
+        // falls into. slots aren't always an hour long, openweathermap's are three
+        pub fn hourly(
+            &self,
+            now: u64,
+            hours: usize,
+            fields: &[api::HourField],
+        ) -> api::HourlyForecast {
+            const HOUR: u64 = 60 * 60;
+            let until = now - now % HOUR + hours as u64 * HOUR;
+            // a slot lasts until the next one starts, the last is taken to be an hour
+            let ends = self
+                .hours
+                .iter()
+                .skip(1)
+                .map(|hour| hour.time)
+                .chain(self.hours.last().map(|hour| hour.time + HOUR));
+            api::HourlyForecast {
+                location: self.location.name.clone(),
+                hours: self
+                    .hours
+                    .iter()
+                    .zip(ends)
+                    .skip_while(|(_, end)| *end <= now)
+                    .take_while(|(hour, _)| hour.time < until)
+                    .map(|(hour, _)| hour.to_api(fields))
+                    .collect(),
+            }
+        }
+    }
+
+    impl Hour {
+        fn to_api(&self, fields: &[api::HourField]) -> api::ForecastHour {
+            let mut selected = api::ForecastHour {
+                time: self.time,
+                ..Default::default()
+            };
+            for field in fields {
+                match field {
+                    api::HourField::Temp => selected.temp = Some(self.temp),
+                    api::HourField::Humidity => selected.humidity = Some(self.humidity),
+                    api::HourField::Pressure => selected.pressure = Some(self.pressure),
+                    api::HourField::WindSpeed => selected.wind_speed = Some(self.wind_speed),
+                    api::HourField::Precip => selected.precip = Some(self.precip),
+                    api::HourField::PrecipProb => selected.precip_prob = Some(self.precip_prob),
+                    api::HourField::Conditions => {
+                        selected.conditions = Some(self.conditions.clone())
+                    }
+                    api::HourField::Icon => selected.icon = Some(self.icon.clone()),
+                }
+            }
+            selected
+        }
+    }
+
+    impl Day {
+        fn to_api(&self) -> api::ForecastDay {
+            api::ForecastDay {
+                date: self.date.clone(),
+                temp: self.temp,
+                temp_max: self.temp_max,
+                temp_min: self.temp_min,
+                conditions: self.conditions.clone(),
+                icon: self.icon.clone(),
+                precip: self.precip,
+                precip_prob: self.precip_prob,
+                wind_speed: self.wind_speed,
             }
         }
     }
+
+    // local calendar date of a unix timestamp
+    pub fn local_date(ts: u64, offset_secs: i64) -> String {
+        chrono::DateTime::from_timestamp(ts as i64 + offset_secs, 0)
+            .map(|dt| dt.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    }
 }
 
+// mirrors the upstream schema, not every field is read yet
+#[allow(dead_code)]
 pub mod vc {
     use serde::Deserialize;
 
-    use super::api;
+    use super::domain;
 
     #[derive(Debug, Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct ResponseVC {
         address: String,
-        current_conditions: CurrentConditionsVC,
+        current_conditions: Option<CurrentConditionsVC>,
+        #[serde(default)]
         alerts: Vec<AlertVC>,
+        #[serde(default)]
         days: Vec<DayVC>,
-        description: String,
+        description: Option<String>,
 
         latitude: f32,
         longitude: f32,
@@ -126,38 +782,52 @@ pub mod vc {
         tzoffset: f32,
     }
 
-    impl ResponseVC {
-        pub fn get_prepared_temp(&self) -> api::PreparedTemp {
-            let current_day = &self.days[0];
-            api::PreparedTemp::new(
-                self.current_conditions.temp,
-                current_day.tempmax,
-                current_day.tempmin,
-                self.current_conditions.humidity,
-                self.current_conditions.pressure,
-                self.current_conditions.windspeed,
-            )
+    impl From<ResponseVC> for domain::WeatherReport {
+        fn from(response: ResponseVC) -> Self {
+            let current = response
+                .current_conditions
+                .as_ref()
+                .map(|cc| domain::Conditions {
+                    observed_at: cc.datetime_epoch,
+                    temp: cc.temp,
+                    humidity: cc.humidity,
+                    pressure: cc.pressure,
+                    wind_speed: cc.windspeed,
+                    conditions: cc.conditions.clone(),
+                    icon: cc.icon.clone(),
+                });
+            let days = response
+                .get_daily_forecase()
+                .iter()
+                .map(DayVC::to_domain)
+                .collect();
+            // hours are nested per day upstream, flattened they cross day boundaries
+            let hours = response
+                .get_daily_forecase()
+                .iter()
+                .flat_map(|day| day.hours.iter().map(HourVC::to_domain))
+                .collect();
+            domain::WeatherReport {
+                location: domain::Location {
+                    name: response.resolved_address,
+                    latitude: response.latitude,
+                    longitude: response.longitude,
+                    tzoffset: response.tzoffset,
+                },
+                current,
+                days,
+                hours,
+                alerts: response.alerts.iter().map(AlertVC::to_domain).collect(),
+                description: response.description,
+                cost: response.query_cost,
+            }
         }
+    }
+
+    impl ResponseVC {
         pub fn get_daily_forecase(&self) -> &[DayVC] {
             &self.days
         }
-        pub fn get_current_timestamp(&self) -> u64 {
-            let cc = &self.current_conditions;
-            let cd = &self.days[0];
-            if cc.datetime_epoch != cd.datetime_epoch {
-                println!(
-                    "warning: current_conditions.datetime_epoch ({}) != days[0].datetime_epoch ({})",
-                    cc.datetime_epoch, cd.datetime_epoch
-                );
-                println!(
-                    "warning: current_conditions.datetime ({}) != days[0].datetime ({})",
-                    &cc.datetime, &cd.datetime
-                );
-            }
-            self.current_conditions
-                .datetime_epoch
-                .max(self.days[0].datetime_epoch)
-        }
     }
 
     #[derive(Debug, Deserialize)]
@@ -195,8 +865,8 @@ pub mod vc {
     #[serde(rename_all = "camelCase")]
     pub struct AlertVC {
         description: String,
-        ends: String,
-        ends_epoch: u64,
+        ends: Option<String>,
+        ends_epoch: Option<u64>,
 
         event: String,
         headline: String,
@@ -208,6 +878,21 @@ pub mod vc {
         onset_epoch: u64,
     }
 
+    impl AlertVC {
+        fn to_domain(&self) -> domain::Alert {
+            domain::Alert {
+                id: self.id.clone(),
+                event: self.event.clone(),
+                headline: self.headline.clone(),
+                description: self.description.clone(),
+                language: self.language.clone(),
+                link: self.link.clone(),
+                onset: self.onset_epoch,
+                ends: self.ends_epoch,
+            }
+        }
+    }
+
     #[derive(Debug, Deserialize)]
     #[serde(rename_all = "camelCase")]
     pub struct DayVC {
@@ -219,12 +904,15 @@ pub mod vc {
         feelslike: f32,
         feelslikemax: f32,
         feelslikemin: f32,
+        #[serde(default)]
         hours: Vec<HourVC>,
         humidity: f32,
         icon: String,
         moonphase: f32,
+        precip: Option<f32>,
+        precipprob: Option<f32>,
         pressure: f32,
-        severerisk: f32,
+        severerisk: Option<f32>,
         snow: f32,
         snowdepth: f32,
         solarenergy: f32,
@@ -239,9 +927,9 @@ pub mod vc {
         tempmax: f32,
         tempmin: f32,
         uvindex: f32,
-        visibility: f32,
+        visibility: Option<f32>,
         winddir: f32,
-        windgust: f32,
+        windgust: Option<f32>,
         windspeed: f32,
     }
 
@@ -252,6 +940,20 @@ pub mod vc {
         pub fn get_date(&self) -> &str {
             &self.datetime
         }
+        fn to_domain(&self) -> domain::Day {
+            let (temp, temp_max, temp_min) = self.get_temps();
+            domain::Day {
+                date: self.get_date().to_string(),
+                temp,
+                temp_max,
+                temp_min,
+                conditions: self.conditions.clone(),
+                icon: self.icon.clone(),
+                precip: self.precip.unwrap_or(0.0),
+                precip_prob: self.precipprob.unwrap_or(0.0),
+                wind_speed: self.windspeed,
+            }
+        }
     }
 
     #[derive(Debug, Deserialize)]
@@ -266,18 +968,670 @@ pub mod vc {
         feelslike: f32,
         humidity: f32,
         icon: String,
+        precip: Option<f32>,
+        precipprob: Option<f32>,
         pressure: f32,
-        severerisk: f32,
-        snow: f32,
-        snowdepth: f32,
-        solarenergy: f32,
-        solarradiation: f32,
+        severerisk: Option<f32>,
+        snow: Option<f32>,
+        snowdepth: Option<f32>,
+        solarenergy: Option<f32>,
+        solarradiation: Option<f32>,
         source: String,
         stations: Option<Vec<String>>,
-        uvindex: f32,
-        visibility: f32,
+        uvindex: Option<f32>,
+        visibility: Option<f32>,
         winddir: f32,
-        windgust: f32,
+        windgust: Option<f32>,
         windspeed: f32,
     }
+
+    impl HourVC {
+        fn to_domain(&self) -> domain::Hour {
+            domain::Hour {
+                time: self.datetime_epoch,
+                temp: self.temp,
+                humidity: self.humidity,
+                pressure: self.pressure,
+                wind_speed: self.windspeed,
+                precip: self.precip.unwrap_or(0.0),
+                precip_prob: self.precipprob.unwrap_or(0.0),
+                conditions: self.conditions.clone(),
+                icon: self.icon.clone(),
+            }
+        }
+    }
+}
+
+pub mod owm {
+    use serde::Deserialize;
+
+    use super::domain;
+
+    #[derive(Debug, Deserialize)]
+    pub struct ResponseOWM {
+        coord: CoordOWM,
+        weather: Vec<WeatherOWM>,
+        main: MainOWM,
+        wind: WindOWM,
+        dt: u64,
+        // seconds from utc
+        timezone: i64,
+        name: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct CoordOWM {
+        lat: f32,
+        lon: f32,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct WeatherOWM {
+        description: String,
+        icon: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct MainOWM {
+        temp: f32,
+        temp_min: f32,
+        temp_max: f32,
+        pressure: f32,
+        humidity: f32,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct WindOWM {
+        // m/s with units=metric
+        speed: f32,
+    }
+
+    impl From<ResponseOWM> for domain::WeatherReport {
+        fn from(response: ResponseOWM) -> Self {
+            let (conditions, icon) = response
+                .weather
+                .first()
+                .map(|w| (w.description.clone(), w.icon.clone()))
+                .unwrap_or_default();
+            domain::WeatherReport {
+                location: domain::Location {
+                    name: response.name,
+                    latitude: response.coord.lat,
+                    longitude: response.coord.lon,
+                    tzoffset: response.timezone as f32 / 3600.0,
+                },
+                current: Some(domain::Conditions {
+                    observed_at: response.dt,
+                    temp: response.main.temp,
+                    humidity: response.main.humidity,
+                    pressure: response.main.pressure,
+                    wind_speed: response.wind.speed * 3.6,
+                    conditions: conditions.clone(),
+                    icon: icon.clone(),
+                }),
+                // the current weather call only knows today's range
+                days: vec![domain::Day {
+                    date: domain::local_date(response.dt, response.timezone),
+                    temp: response.main.temp,
+                    temp_max: response.main.temp_max,
+                    temp_min: response.main.temp_min,
+                    conditions,
+                    icon,
+                    precip: 0.0,
+                    precip_prob: 0.0,
+                    wind_speed: response.wind.speed * 3.6,
+                }],
+                hours: Vec::new(),
+                alerts: Vec::new(),
+                description: None,
+                cost: 1.0,
+            }
+        }
+    }
+
+    // 5 day / 3 hour forecast
+    #[derive(Debug, Deserialize)]
+    pub struct ForecastOWM {
+        list: Vec<SlotOWM>,
+        city: CityOWM,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct SlotOWM {
+        dt: u64,
+        main: MainOWM,
+        weather: Vec<WeatherOWM>,
+        wind: WindOWM,
+        // probability of precipitation, 0..1
+        #[serde(default)]
+        pop: f32,
+        rain: Option<PrecipOWM>,
+        snow: Option<PrecipOWM>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct PrecipOWM {
+        #[serde(rename = "3h", default)]
+        three_hours: f32,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct CityOWM {
+        name: String,
+        coord: CoordOWM,
+        timezone: i64,
+    }
+
+    impl From<ForecastOWM> for domain::WeatherReport {
+        // folds the 3 hour slots into local calendar days
+        fn from(response: ForecastOWM) -> Self {
+            let offset = response.city.timezone;
+            let mut days: Vec<(domain::Day, u32)> = Vec::new();
+            let mut hours = Vec::new();
+            for slot in &response.list {
+                let date = domain::local_date(slot.dt, offset);
+                let precip = slot.rain.as_ref().map_or(0.0, |p| p.three_hours)
+                    + slot.snow.as_ref().map_or(0.0, |p| p.three_hours);
+                let wind_speed = slot.wind.speed * 3.6;
+                let (conditions, icon) = slot
+                    .weather
+                    .first()
+                    .map(|w| (w.description.clone(), w.icon.clone()))
+                    .unwrap_or_default();
+                // the finest resolution this api offers is one slot per 3 hours
+                hours.push(domain::Hour {
+                    time: slot.dt,
+                    temp: slot.main.temp,
+                    humidity: slot.main.humidity,
+                    pressure: slot.main.pressure,
+                    wind_speed,
+                    precip,
+                    precip_prob: slot.pop * 100.0,
+                    conditions: conditions.clone(),
+                    icon: icon.clone(),
+                });
+                match days.last_mut() {
+                    Some((day, slots)) if day.date == date => {
+                        day.temp += slot.main.temp;
+                        day.temp_max = day.temp_max.max(slot.main.temp_max);
+                        day.temp_min = day.temp_min.min(slot.main.temp_min);
+                        day.precip += precip;
+                        day.precip_prob = day.precip_prob.max(slot.pop * 100.0);
+                        day.wind_speed = day.wind_speed.max(wind_speed);
+                        *slots += 1;
+                    }
+                    _ => {
+                        let day = domain::Day {
+                            date,
+                            temp: slot.main.temp,
+                            temp_max: slot.main.temp_max,
+                            temp_min: slot.main.temp_min,
+                            conditions,
+                            icon,
+                            precip,
+                            precip_prob: slot.pop * 100.0,
+                            wind_speed,
+                        };
+                        days.push((day, 1));
+                    }
+                }
+            }
+            domain::WeatherReport {
+                location: domain::Location {
+                    name: response.city.name,
+                    latitude: response.city.coord.lat,
+                    longitude: response.city.coord.lon,
+                    tzoffset: offset as f32 / 3600.0,
+                },
+                current: None,
+                days: days
+                    .into_iter()
+                    .map(|(day, slots)| domain::Day {
+                        temp: day.temp / slots as f32,
+                        ..day
+                    })
+                    .collect(),
+                hours,
+                alerts: Vec::new(),
+                description: None,
+                cost: 1.0,
+            }
+        }
+    }
+}
+
+pub mod om {
+    use anyhow::{Result, bail};
+    use serde::Deserialize;
+
+    use super::{WeatherLang, domain};
+
+    #[derive(Debug, Deserialize)]
+    pub struct GeocodingOM {
+        #[serde(default)]
+        pub results: Vec<PlaceOM>,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct PlaceOM {
+        pub name: String,
+        pub latitude: f32,
+        pub longitude: f32,
+        pub country: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ResponseOM {
+        latitude: f32,
+        longitude: f32,
+        utc_offset_seconds: i64,
+        current: Option<CurrentOM>,
+        daily: DailyOM,
+        hourly: Option<HourlyOM>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct CurrentOM {
+        // unix seconds with timeformat=unixtime
+        time: u64,
+        temperature_2m: f32,
+        relative_humidity_2m: f32,
+        pressure_msl: f32,
+        // km/h by default
+        wind_speed_10m: f32,
+        weather_code: u8,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct DailyOM {
+        // local midnight as unix seconds
+        time: Vec<u64>,
+        temperature_2m_max: Vec<f32>,
+        temperature_2m_min: Vec<f32>,
+        weather_code: Vec<u8>,
+        precipitation_sum: Vec<Option<f32>>,
+        precipitation_probability_max: Vec<Option<f32>>,
+        wind_speed_10m_max: Vec<f32>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct HourlyOM {
+        time: Vec<u64>,
+        temperature_2m: Vec<f32>,
+        relative_humidity_2m: Vec<f32>,
+        pressure_msl: Vec<f32>,
+        wind_speed_10m: Vec<f32>,
+        precipitation: Vec<Option<f32>>,
+        precipitation_probability: Vec<Option<f32>>,
+        weather_code: Vec<u8>,
+    }
+
+    // every variable comes as its own array, one value per timestamp. indexing
+    // them by the timestamps is only safe once they all line up
+    fn check_lengths(series: &str, times: usize, variables: &[(&str, usize)]) -> Result<()> {
+        for (variable, len) in variables {
+            if *len != times {
+                bail!(
+                    "{}.{} has {} values for {} timestamps",
+                    series,
+                    variable,
+                    len,
+                    times
+                );
+            }
+        }
+        Ok(())
+    }
+
+    impl DailyOM {
+        fn check(&self) -> Result<()> {
+            check_lengths(
+                "daily",
+                self.time.len(),
+                &[
+                    ("temperature_2m_max", self.temperature_2m_max.len()),
+                    ("temperature_2m_min", self.temperature_2m_min.len()),
+                    ("weather_code", self.weather_code.len()),
+                    ("precipitation_sum", self.precipitation_sum.len()),
+                    (
+                        "precipitation_probability_max",
+                        self.precipitation_probability_max.len(),
+                    ),
+                    ("wind_speed_10m_max", self.wind_speed_10m_max.len()),
+                ],
+            )
+        }
+    }
+
+    impl HourlyOM {
+        fn check(&self) -> Result<()> {
+            check_lengths(
+                "hourly",
+                self.time.len(),
+                &[
+                    ("temperature_2m", self.temperature_2m.len()),
+                    ("relative_humidity_2m", self.relative_humidity_2m.len()),
+                    ("pressure_msl", self.pressure_msl.len()),
+                    ("wind_speed_10m", self.wind_speed_10m.len()),
+                    ("precipitation", self.precipitation.len()),
+                    (
+                        "precipitation_probability",
+                        self.precipitation_probability.len(),
+                    ),
+                    ("weather_code", self.weather_code.len()),
+                ],
+            )
+        }
+
+        fn to_domain(&self, lang: WeatherLang) -> Vec<domain::Hour> {
+            (0..self.time.len())
+                .map(|i| {
+                    let (conditions, icon) = describe_wmo_code(self.weather_code[i], lang);
+                    domain::Hour {
+                        time: self.time[i],
+                        temp: self.temperature_2m[i],
+                        humidity: self.relative_humidity_2m[i],
+                        pressure: self.pressure_msl[i],
+                        wind_speed: self.wind_speed_10m[i],
+                        precip: self.precipitation[i].unwrap_or(0.0),
+                        precip_prob: self.precipitation_probability[i].unwrap_or(0.0),
+                        conditions: conditions.to_string(),
+                        icon: icon.to_string(),
+                    }
+                })
+                .collect()
+        }
+    }
+
+    impl ResponseOM {
+        pub fn into_report(
+            self,
+            place: PlaceOM,
+            lang: WeatherLang,
+        ) -> Result<domain::WeatherReport> {
+            self.daily.check()?;
+            if let Some(hourly) = &self.hourly {
+                hourly.check()?;
+            }
+            let name = match place.country {
+                Some(country) => format!("{}, {}", place.name, country),
+                None => place.name,
+            };
+            let current = self.current.as_ref().map(|current| {
+                let (conditions, icon) = describe_wmo_code(current.weather_code, lang);
+                domain::Conditions {
+                    observed_at: current.time,
+                    temp: current.temperature_2m,
+                    humidity: current.relative_humidity_2m,
+                    pressure: current.pressure_msl,
+                    wind_speed: current.wind_speed_10m,
+                    conditions: conditions.to_string(),
+                    icon: icon.to_string(),
+                }
+            });
+            let daily = &self.daily;
+            let days = (0..daily.time.len())
+                .map(|i| {
+                    let (conditions, icon) = describe_wmo_code(daily.weather_code[i], lang);
+                    let (temp_max, temp_min) =
+                        (daily.temperature_2m_max[i], daily.temperature_2m_min[i]);
+                    domain::Day {
+                        date: domain::local_date(daily.time[i], self.utc_offset_seconds),
+                        // open-meteo has no daily mean in this call
+                        temp: (temp_max + temp_min) / 2.0,
+                        temp_max,
+                        temp_min,
+                        conditions: conditions.to_string(),
+                        icon: icon.to_string(),
+                        precip: daily.precipitation_sum[i].unwrap_or(0.0),
+                        precip_prob: daily.precipitation_probability_max[i].unwrap_or(0.0),
+                        wind_speed: daily.wind_speed_10m_max[i],
+                    }
+                })
+                .collect();
+            Ok(domain::WeatherReport {
+                location: domain::Location {
+                    name,
+                    latitude: self.latitude,
+                    longitude: self.longitude,
+                    tzoffset: self.utc_offset_seconds as f32 / 3600.0,
+                },
+                current,
+                days,
+                hours: self
+                    .hourly
+                    .as_ref()
+                    .map(|hourly| hourly.to_domain(lang))
+                    .unwrap_or_default(),
+                // open-meteo does not publish alerts
+                alerts: Vec::new(),
+                description: None,
+                // geocoding and forecast calls, open-meteo itself does not bill
+                cost: 2.0,
+            })
+        }
+    }
+
+    // wmo weather interpretation codes, icons named like the visual crossing ones
+    pub fn describe_wmo_code(code: u8, lang: WeatherLang) -> (&'static str, &'static str) {
+        let (en, ru, icon) = match code {
+            0 => ("Clear", "Ясно", "clear-day"),
+            1 | 2 => (
+                "Partially cloudy",
+                "Переменная облачность",
+                "partly-cloudy-day",
+            ),
+            3 => ("Overcast", "Пасмурно", "cloudy"),
+            45 | 48 => ("Fog", "Туман", "fog"),
+            51..=57 => ("Drizzle", "Морось", "rain"),
+            61..=67 | 80..=82 => ("Rain", "Дождь", "rain"),
+            71..=77 | 85 | 86 => ("Snow", "Снег", "snow"),
+            95..=99 => ("Thunderstorm", "Гроза", "thunder-rain"),
+            _ => ("Unknown", "Неизвестно", "cloudy"),
+        };
+        match lang {
+            WeatherLang::En => (en, icon),
+            WeatherLang::Ru => (ru, icon),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_picks_the_most_preferred_supported_language() {
+        let lang = WeatherLang::negotiate(None, Some("ru-RU,ru;q=0.9,en;q=0.8"));
+        assert_eq!(lang, WeatherLang::Ru);
+    }
+
+    #[test]
+    fn negotiate_skips_refused_languages() {
+        assert_eq!(
+            WeatherLang::negotiate(None, Some("en;q=0")),
+            WeatherLang::En
+        );
+        let lang = WeatherLang::negotiate(None, Some("en;q=0, ru;q=0.1"));
+        assert_eq!(lang, WeatherLang::Ru);
+    }
+
+    #[test]
+    fn negotiate_skips_unsupported_languages() {
+        let lang = WeatherLang::negotiate(None, Some("fr, ru;q=0.5"));
+        assert_eq!(lang, WeatherLang::Ru);
+    }
+
+    #[test]
+    fn negotiate_prefers_an_explicit_param() {
+        assert_eq!(
+            WeatherLang::negotiate(Some("en"), Some("ru")),
+            WeatherLang::En
+        );
+        assert_eq!(
+            WeatherLang::negotiate(Some("ru-RU"), Some("en")),
+            WeatherLang::Ru
+        );
+        // an unsupported param doesn't count as a choice
+        assert_eq!(
+            WeatherLang::negotiate(Some("fr"), Some("ru")),
+            WeatherLang::Ru
+        );
+    }
+
+    fn open_meteo(hourly_temps: &str) -> om::ResponseOM {
+        let json = format!(
+            r#"{{
+                "latitude": 55.75, "longitude": 37.62, "utc_offset_seconds": 10800,
+                "daily": {{
+                    "time": [1704056400],
+                    "temperature_2m_max": [-2.0], "temperature_2m_min": [-8.0],
+                    "weather_code": [71], "precipitation_sum": [25.4],
+                    "precipitation_probability_max": [40.0], "wind_speed_10m_max": [12.0]
+                }},
+                "hourly": {{
+                    "time": [1704056400, 1704060000],
+                    "temperature_2m": {},
+                    "relative_humidity_2m": [80.0, 81.0], "pressure_msl": [1010.0, 1011.0],
+                    "wind_speed_10m": [5.0, 6.0], "precipitation": [0.0, null],
+                    "precipitation_probability": [10.0, 20.0], "weather_code": [3, 71]
+                }}
+            }}"#,
+            hourly_temps
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn place() -> om::PlaceOM {
+        om::PlaceOM {
+            name: "Moscow".to_string(),
+            latitude: 55.75,
+            longitude: 37.62,
+            country: Some("Russia".to_string()),
+        }
+    }
+
+    #[test]
+    fn open_meteo_arrays_become_days_and_hours() {
+        let report = open_meteo("[-5.0, -6.0]")
+            .into_report(place(), WeatherLang::En)
+            .unwrap();
+        assert_eq!(report.days.len(), 1);
+        assert_eq!(report.days[0].temp, -5.0);
+        assert_eq!(report.hours.len(), 2);
+        assert_eq!(report.hours[1].temp, -6.0);
+        assert_eq!(report.hours[1].conditions, "Snow");
+    }
+
+    #[test]
+    fn open_meteo_arrays_of_different_lengths_are_a_schema_error() {
+        let error = open_meteo("[-5.0]")
+            .into_report(place(), WeatherLang::En)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "hourly.temperature_2m has 1 values for 2 timestamps"
+        );
+    }
+
+    const HOUR: u64 = 60 * 60;
+    // 2024-01-01 00:00 utc
+    const NEW_YEAR: u64 = 1_704_067_200;
+
+    fn hourly_report(step: u64) -> domain::WeatherReport {
+        let mut report = open_meteo("[-5.0, -6.0]")
+            .into_report(place(), WeatherLang::En)
+            .unwrap();
+        let hour = report.hours[0].clone();
+        report.hours = (0..48)
+            .map(|i| domain::Hour {
+                time: NEW_YEAR + i * step,
+                ..hour.clone()
+            })
+            .collect();
+        report
+    }
+
+    fn times(report: &domain::WeatherReport, now: u64, hours: usize) -> Vec<u64> {
+        report
+            .hourly(now, hours, &[])
+            .hours
+            .iter()
+            .map(|hour| (hour.time - NEW_YEAR) / HOUR)
+            .collect()
+    }
+
+    #[test]
+    fn hourly_starts_with_the_current_hour() {
+        let report = hourly_report(HOUR);
+        assert_eq!(times(&report, NEW_YEAR + 90 * 60, 3), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn hourly_counts_hours_not_slots() {
+        // 04:30 falls into the 03:00 slot, the next 6 hours end at 10:00
+        let report = hourly_report(3 * HOUR);
+        assert_eq!(
+            times(&report, NEW_YEAR + 4 * HOUR + 30 * 60, 6),
+            vec![3, 6, 9]
+        );
+        assert_eq!(times(&report, NEW_YEAR, 24).len(), 8);
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn temperatures_convert_to_fahrenheit_and_kelvin() {
+        for (celsius, fahrenheit, kelvin) in [(0.0, 32.0, 273.15), (-40.0, -40.0, 233.15)] {
+            assert_close(WeatherUnits::Us.temp(celsius), fahrenheit);
+            assert_close(WeatherUnits::Base.temp(celsius), kelvin);
+            assert_close(WeatherUnits::Metric.temp(celsius), celsius);
+            assert_close(WeatherUnits::Uk.temp(celsius), celsius);
+        }
+    }
+
+    #[test]
+    fn speeds_convert_to_mph_and_metres_per_second() {
+        assert_close(WeatherUnits::Metric.speed(36.0), 36.0);
+        assert_close(WeatherUnits::Us.speed(1.609_344), 1.0);
+        assert_close(WeatherUnits::Uk.speed(100.0), 62.137);
+        assert_close(WeatherUnits::Base.speed(36.0), 10.0);
+    }
+
+    #[test]
+    fn pressure_and_precipitation_convert_for_us_only() {
+        assert_close(WeatherUnits::Us.pressure(1013.25), 29.921);
+        assert_close(WeatherUnits::Us.precip(25.4), 1.0);
+        for units in [WeatherUnits::Metric, WeatherUnits::Uk, WeatherUnits::Base] {
+            assert_close(units.pressure(1013.25), 1013.25);
+            assert_close(units.precip(25.4), 25.4);
+        }
+    }
+
+    #[test]
+    fn reports_convert_every_measurement() {
+        let report = open_meteo("[-5.0, -6.0]")
+            .into_report(place(), WeatherLang::En)
+            .unwrap();
+        let us = report.in_units(WeatherUnits::Us);
+        assert_close(us.days[0].temp_max, 28.4);
+        assert_close(us.days[0].precip, 1.0);
+        assert_close(us.days[0].wind_speed, 7.456);
+        assert_close(us.hours[0].temp, 23.0);
+        assert_close(us.hours[0].pressure, 29.826);
+        let uk = report.in_units(WeatherUnits::Uk);
+        assert_close(uk.days[0].temp_max, -2.0);
+        assert_close(uk.hours[0].pressure, 1010.0);
+        assert_close(uk.days[0].wind_speed, 7.456);
+        let metric = report.in_units(WeatherUnits::Metric);
+        assert_close(metric.hours[0].pressure, 1010.0);
+        assert_close(metric.days[0].precip, 25.4);
+    }
 }
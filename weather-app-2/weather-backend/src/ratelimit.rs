@@ -0,0 +1,284 @@
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use lru::LruCache;
+use redis::aio::MultiplexedConnection;
+use tokio::sync::Mutex;
+
+use crate::config::{ClientKey, Config, RateLimitBackend};
+use crate::error::WeatherError;
+use crate::state::AppState;
+
+// buckets of clients not seen for a while are dropped, which only refills them early
+const TRACKED_CLIENTS: usize = 100_000;
+
+// refills in milliseconds so the same math runs in lua on redis
+const TAKE_SCRIPT: &str = r"
+local burst = tonumber(ARGV[1])
+local per_ms = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or burst
+local at = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - at) * per_ms)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / per_ms)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst / per_ms))
+return wait
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    // seconds until a token is back
+    Deny(u64),
+}
+
+// a client may spend `burst` requests at once, then `per_minute` spread over a minute
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn per_ms(&self) -> f64 {
+        self.per_minute as f64 / 60_000.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    at_ms: u64,
+}
+
+impl Bucket {
+    fn full(limit: &Limit, now_ms: u64) -> Bucket {
+        Bucket {
+            tokens: limit.burst as f64,
+            at_ms: now_ms,
+        }
+    }
+
+    fn take(&mut self, limit: &Limit, now_ms: u64) -> Decision {
+        let elapsed = now_ms.saturating_sub(self.at_ms) as f64;
+        self.tokens = (self.tokens + elapsed * limit.per_ms()).min(limit.burst as f64);
+        self.at_ms = now_ms;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Decision::Allow;
+        }
+        let wait_ms = ((1.0 - self.tokens) / limit.per_ms()).ceil() as u64;
+        Decision::Deny(wait_ms.div_ceil(1000))
+    }
+}
+
+#[async_trait]
+pub trait RateStore: Send + Sync {
+    async fn take(&self, client: &str) -> Decision;
+}
+
+pub struct RateLimiter {
+    store: Box<dyn RateStore>,
+    key: ClientKey,
+}
+
+impl RateLimiter {
+    pub async fn from_config(config: &Config) -> Result<RateLimiter> {
+        let limit = Limit {
+            burst: config.rate_limit_burst,
+            per_minute: config.rate_limit_per_minute,
+        };
+        let store: Box<dyn RateStore> = match config.rate_limit_backend {
+            RateLimitBackend::Memory => Box::new(MemoryRateStore::new(limit)),
+            RateLimitBackend::Redis => {
+                Box::new(RedisRateStore::new(&config.redis_url, limit).await?)
+            }
+            RateLimitBackend::None => Box::new(NoLimit),
+        };
+        Ok(RateLimiter {
+            store,
+            key: config.rate_limit_key,
+        })
+    }
+
+    // api keys aren't verified here, so keying by them only holds behind a
+    // gateway that does. anyone without one is limited by address
+    fn client(&self, request: &Request, addr: SocketAddr) -> String {
+        let api_key = match self.key {
+            ClientKey::ApiKey => request
+                .headers()
+                .get("x-api-key")
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty()),
+            ClientKey::Ip => None,
+        };
+        match api_key {
+            Some(api_key) => format!("key:{}", api_key),
+            None => format!("ip:{}", addr.ip()),
+        }
+    }
+}
+
+pub async fn limit(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response, WeatherError> {
+    let client = state.limiter.client(&request, addr);
+    match state.limiter.store.take(&client).await {
+        Decision::Allow => Ok(next.run(request).await),
+        Decision::Deny(retry_after) => Err(WeatherError::RateLimited(retry_after)),
+    }
+}
+
+pub struct NoLimit;
+
+#[async_trait]
+impl RateStore for NoLimit {
+    async fn take(&self, _client: &str) -> Decision {
+        Decision::Allow
+    }
+}
+
+pub struct MemoryRateStore {
+    limit: Limit,
+    buckets: Mutex<LruCache<String, Bucket>>,
+}
+
+impl MemoryRateStore {
+    pub fn new(limit: Limit) -> MemoryRateStore {
+        MemoryRateStore {
+            limit,
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(TRACKED_CLIENTS).unwrap())),
+        }
+    }
+}
+
+#[async_trait]
+impl RateStore for MemoryRateStore {
+    async fn take(&self, client: &str) -> Decision {
+        let now_ms = now_ms();
+        let mut buckets = self.buckets.lock().await;
+        let bucket =
+            buckets.get_or_insert_mut(client.to_string(), || Bucket::full(&self.limit, now_ms));
+        bucket.take(&self.limit, now_ms)
+    }
+}
+
+// shared by every instance, so a client can't spread requests across them
+pub struct RedisRateStore {
+    con: MultiplexedConnection,
+    limit: Limit,
+    script: redis::Script,
+}
+
+impl RedisRateStore {
+    const PREFIX: &str = "weather:ratelimit";
+
+    pub async fn new(url: &str, limit: Limit) -> Result<RedisRateStore> {
+        let client = redis::Client::open(url)?;
+        let con = client.get_multiplexed_tokio_connection().await?;
+        Ok(RedisRateStore {
+            con,
+            limit,
+            script: redis::Script::new(TAKE_SCRIPT),
+        })
+    }
+
+    async fn try_take(&self, client: &str) -> Result<Decision> {
+        let mut con = self.con.clone();
+        let wait_ms: u64 = self
+            .script
+            .key(format!("{}:{}", Self::PREFIX, client))
+            .arg(self.limit.burst)
+            .arg(self.limit.per_ms())
+            .invoke_async(&mut con)
+            .await?;
+        if wait_ms == 0 {
+            return Ok(Decision::Allow);
+        }
+        Ok(Decision::Deny(wait_ms.div_ceil(1000)))
+    }
+}
+
+#[async_trait]
+impl RateStore for RedisRateStore {
+    // an unreachable redis shouldn't take the api down with it
+    async fn take(&self, client: &str) -> Decision {
+        self.try_take(client).await.unwrap_or_else(|e| {
+            eprintln!("redis rate limit failed: {}", e);
+            Decision::Allow
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        burst: 3,
+        per_minute: 60,
+    };
+
+    #[test]
+    fn bucket_allows_a_burst_then_denies() {
+        let mut bucket = Bucket::full(&LIMIT, 0);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(&LIMIT, 0), Decision::Allow);
+        }
+        assert_eq!(bucket.take(&LIMIT, 0), Decision::Deny(1));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let mut bucket = Bucket::full(&LIMIT, 0);
+        for _ in 0..3 {
+            bucket.take(&LIMIT, 0);
+        }
+        assert_eq!(bucket.take(&LIMIT, 500), Decision::Deny(1));
+        assert_eq!(bucket.take(&LIMIT, 1_000), Decision::Allow);
+    }
+
+    #[test]
+    fn bucket_never_holds_more_than_the_burst() {
+        let mut bucket = Bucket::full(&LIMIT, 0);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(&LIMIT, 3_600_000), Decision::Allow);
+        }
+        assert!(matches!(bucket.take(&LIMIT, 3_600_000), Decision::Deny(_)));
+    }
+
+    #[tokio::test]
+    async fn memory_store_limits_clients_separately() {
+        let store = MemoryRateStore::new(Limit {
+            burst: 1,
+            per_minute: 1,
+        });
+        assert_eq!(store.take("ip:1.1.1.1").await, Decision::Allow);
+        assert_eq!(store.take("ip:2.2.2.2").await, Decision::Allow);
+        assert_eq!(store.take("ip:1.1.1.1").await, Decision::Deny(60));
+    }
+}
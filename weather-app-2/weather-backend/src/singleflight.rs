@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+#[derive(Debug)]
+pub struct SingleFlight<K, V> {
+    calls: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> SingleFlight<K, V> {
+    pub fn new() -> SingleFlight<K, V> {
+        SingleFlight {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // the first caller for a key runs `f`, everyone arriving while it runs awaits the same value
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let call = {
+            let mut calls = self.calls.lock().unwrap();
+            calls.entry(key.clone()).or_default().clone()
+        };
+
+        let value = call.get_or_init(f).await.clone();
+
+        let mut calls = self.calls.lock().unwrap();
+        if calls
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &call))
+        {
+            calls.remove(&key);
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::task::JoinSet;
+
+    use super::*;
+
+    // counts its runs and stays in flight long enough for every caller to join
+    async fn slow(runs: &AtomicUsize, value: &'static str) -> &'static str {
+        runs.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        value
+    }
+
+    fn spawn_calls(
+        calls: &mut JoinSet<&'static str>,
+        flight: &Arc<SingleFlight<&'static str, &'static str>>,
+        runs: &Arc<AtomicUsize>,
+        key: &'static str,
+        count: usize,
+    ) {
+        for _ in 0..count {
+            let (flight, runs) = (flight.clone(), runs.clone());
+            calls.spawn(async move { flight.run(key, || slow(&runs, key)).await });
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_for_a_key_run_once() {
+        let flight = Arc::new(SingleFlight::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut calls = JoinSet::new();
+        spawn_calls(&mut calls, &flight, &runs, "moscow", 10);
+        let values = calls.join_all().await;
+        assert_eq!(values, vec!["moscow"; 10]);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn different_keys_run_separately() {
+        let flight = Arc::new(SingleFlight::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut calls = JoinSet::new();
+        spawn_calls(&mut calls, &flight, &runs, "moscow", 5);
+        spawn_calls(&mut calls, &flight, &runs, "london", 5);
+        let mut values = calls.join_all().await;
+        values.sort();
+        assert_eq!(values, [vec!["london"; 5], vec!["moscow"; 5]].concat());
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn a_finished_call_is_not_reused() {
+        let flight = SingleFlight::new();
+        let runs = AtomicUsize::new(0);
+        flight.run("moscow", || slow(&runs, "first")).await;
+        let value = flight.run("moscow", || slow(&runs, "second")).await;
+        assert_eq!(value, "second");
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,52 @@
+use std::sync::Arc;
+
+use crate::api::WeatherProvider;
+use crate::buckets::TimeBuckets;
+use crate::budget::Budget;
+use crate::cache::{CacheEntry, CacheService};
+use crate::config::Config;
+use crate::error::WeatherError;
+use crate::locations::Locations;
+use crate::models::CacheKey;
+use crate::ratelimit::RateLimiter;
+use crate::singleflight::SingleFlight;
+use crate::storage::Storage;
+
+pub type FetchResult = Result<CacheEntry, WeatherError>;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub provider: Arc<dyn WeatherProvider>,
+    pub cache: Arc<CacheService>,
+    pub buckets: Arc<TimeBuckets>,
+    pub locations: Arc<Locations>,
+    pub storage: Storage,
+    pub limiter: Arc<RateLimiter>,
+    pub budget: Arc<Budget>,
+    pub inflight: Arc<SingleFlight<CacheKey, FetchResult>>,
+}
+
+impl AppState {
+    pub fn new(
+        config: Config,
+        provider: Arc<dyn WeatherProvider>,
+        cache: CacheService,
+        storage: Storage,
+        limiter: RateLimiter,
+        budget: Budget,
+        locations: Locations,
+    ) -> AppState {
+        AppState {
+            provider,
+            buckets: Arc::new(TimeBuckets::new(config.bucket_width, config.cache_capacity)),
+            storage,
+            limiter: Arc::new(limiter),
+            budget: Arc::new(budget),
+            locations: Arc::new(locations),
+            config: Arc::new(config),
+            cache: Arc::new(cache),
+            inflight: Arc::new(SingleFlight::new()),
+        }
+    }
+}
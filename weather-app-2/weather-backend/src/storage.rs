@@ -1 +1,300 @@
+use std::sync::{Arc, Mutex};
 
+use anyhow::{Result, anyhow};
+use chrono::NaiveDate;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::locations::KnownLocation;
+use crate::models::api::{Observation, PreparedTemp};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS observations (
+        id INTEGER PRIMARY KEY,
+        location_id TEXT NOT NULL,
+        query_name TEXT NOT NULL,
+        location_name TEXT NOT NULL,
+        observed_at INTEGER NOT NULL,
+        temp REAL NOT NULL,
+        temp_max REAL NOT NULL,
+        temp_min REAL NOT NULL,
+        humidity REAL NOT NULL,
+        pressure REAL NOT NULL,
+        wind_speed REAL NOT NULL,
+        conditions TEXT NOT NULL,
+        UNIQUE (location_id, observed_at)
+    );
+    CREATE INDEX IF NOT EXISTS observations_by_name ON observations (query_name, observed_at);
+    CREATE TABLE IF NOT EXISTS locations (
+        name TEXT PRIMARY KEY,
+        location_id TEXT NOT NULL,
+        tzoffset REAL NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS upstream_spend (
+        day TEXT PRIMARY KEY,
+        cost REAL NOT NULL
+    );
+";
+
+// every current conditions report we paid for, kept so trends can be charted
+// without upstream historical queries. values are stored metric. also keeps
+// the daily upstream spend and, without a redis, the locations names resolved to
+#[derive(Clone)]
+pub struct Storage {
+    con: Arc<Mutex<Connection>>,
+}
+
+pub struct Record<'a> {
+    pub location_id: &'a str,
+    // the name the upstream was asked for, history is found by it while the name's id isn't known
+    pub query_name: &'a str,
+    pub location_name: &'a str,
+    pub observed_at: u64,
+}
+
+impl Storage {
+    pub fn open(path: &str) -> Result<Storage> {
+        let con = Connection::open(path)?;
+        con.execute_batch(SCHEMA)?;
+        Ok(Storage {
+            con: Arc::new(Mutex::new(con)),
+        })
+    }
+
+    // the same observation fetched twice is stored once
+    pub async fn record(&self, record: Record<'_>, temp: &PreparedTemp) -> Result<()> {
+        let location_id = record.location_id.to_string();
+        let query_name = record.query_name.to_string();
+        let location_name = record.location_name.to_string();
+        let observed_at = record.observed_at as i64;
+        let temp = temp.clone();
+        self.with_connection(move |con| {
+            con.execute(
+                "INSERT OR IGNORE INTO observations (location_id, query_name, location_name,
+                    observed_at, temp, temp_max, temp_min, humidity, pressure, wind_speed, conditions)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    location_id,
+                    query_name,
+                    location_name,
+                    observed_at,
+                    temp.temp,
+                    temp.temp_max,
+                    temp.temp_min,
+                    temp.humidity,
+                    temp.pressure,
+                    temp.wind_speed,
+                    temp.conditions,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    // oldest first, inclusive on both ends
+    pub async fn history(
+        &self,
+        location_id: &str,
+        query_name: &str,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<Observation>> {
+        let (location_id, query_name) = (location_id.to_string(), query_name.to_string());
+        self.with_connection(move |con| {
+            let mut statement = con.prepare(
+                "SELECT location_name, observed_at, temp, temp_max, temp_min, humidity, pressure,
+                    wind_speed, conditions
+                 FROM observations
+                 WHERE (location_id = ?1 OR query_name = ?2) AND observed_at BETWEEN ?3 AND ?4
+                 ORDER BY observed_at",
+            )?;
+            let rows = statement.query_map(
+                params![location_id, query_name, from as i64, to as i64],
+                |row| {
+                    Ok(Observation {
+                        location: row.get(0)?,
+                        observed_at: row.get::<_, i64>(1)? as u64,
+                        temp: row.get(2)?,
+                        temp_max: row.get(3)?,
+                        temp_min: row.get(4)?,
+                        humidity: row.get(5)?,
+                        pressure: row.get(6)?,
+                        wind_speed: row.get(7)?,
+                        conditions: row.get(8)?,
+                    })
+                },
+            )?;
+            Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+        })
+        .await
+    }
+
+    pub async fn add_spend(&self, day: NaiveDate, cost: f64) -> Result<()> {
+        let day = day.to_string();
+        self.with_connection(move |con| {
+            con.execute(
+                "INSERT INTO upstream_spend (day, cost) VALUES (?1, ?2)
+                 ON CONFLICT (day) DO UPDATE SET cost = cost + excluded.cost",
+                params![day, cost],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn spend(&self, day: NaiveDate) -> Result<f64> {
+        let day = day.to_string();
+        self.with_connection(move |con| {
+            let cost = con
+                .query_row(
+                    "SELECT cost FROM upstream_spend WHERE day = ?1",
+                    params![day],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(cost.unwrap_or(0.0))
+        })
+        .await
+    }
+
+    pub async fn location(&self, name: &str) -> Result<Option<KnownLocation>> {
+        let name = name.to_string();
+        self.with_connection(move |con| {
+            Ok(con
+                .query_row(
+                    "SELECT location_id, tzoffset FROM locations WHERE name = ?1",
+                    params![name],
+                    |row| {
+                        Ok(KnownLocation {
+                            id: row.get(0)?,
+                            tzoffset: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    pub async fn add_location(&self, name: &str, location: &KnownLocation) -> Result<()> {
+        let name = name.to_string();
+        let location = location.clone();
+        self.with_connection(move |con| {
+            con.execute(
+                "INSERT INTO locations (name, location_id, tzoffset) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE
+                 SET location_id = excluded.location_id, tzoffset = excluded.tzoffset",
+                params![name, location.id, location.tzoffset],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    // sqlite blocks, keep it off the async workers
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let con = self.con.clone();
+        tokio::task::spawn_blocking(move || {
+            let con = con
+                .lock()
+                .map_err(|_| anyhow!("history store lock poisoned"))?;
+            f(&con)
+        })
+        .await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp(temp: f32) -> PreparedTemp {
+        PreparedTemp {
+            conditions: "Snow".to_string(),
+            ..PreparedTemp::new(temp, temp + 2.0, temp - 2.0, 80.0, 1010.0, 5.0, false)
+        }
+    }
+
+    async fn record(storage: &Storage, observed_at: u64, value: f32) {
+        let record = Record {
+            location_id: "55.76,37.62",
+            query_name: "moscow",
+            location_name: "Moscow, Russia",
+            observed_at,
+        };
+        storage.record(record, &temp(value)).await.unwrap();
+    }
+
+    async fn observed(storage: &Storage, location_id: &str, from: u64, to: u64) -> Vec<u64> {
+        storage
+            .history(location_id, "nowhere", from, to)
+            .await
+            .unwrap()
+            .iter()
+            .map(|observation| observation.observed_at)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recorded_observations_come_back() {
+        let storage = Storage::open(":memory:").unwrap();
+        record(&storage, 100, -5.0).await;
+        let history = storage.history("55.76,37.62", "", 0, 200).await.unwrap();
+        assert_eq!(history.len(), 1);
+        let observation = &history[0];
+        assert_eq!(observation.location, "Moscow, Russia");
+        assert_eq!(observation.observed_at, 100);
+        assert_eq!(
+            (observation.temp, observation.temp_max, observation.temp_min),
+            (-5.0, -3.0, -7.0)
+        );
+        assert_eq!(observation.conditions, "Snow");
+        // found by the queried name too, before its id is known
+        let by_name = storage.history("name:moscow", "moscow", 0, 200).await;
+        assert_eq!(by_name.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn the_same_observation_is_stored_once() {
+        let storage = Storage::open(":memory:").unwrap();
+        record(&storage, 100, -5.0).await;
+        record(&storage, 100, -6.0).await;
+        let history = storage.history("55.76,37.62", "", 0, 200).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].temp, -5.0);
+    }
+
+    #[tokio::test]
+    async fn history_bounds_are_inclusive() {
+        let storage = Storage::open(":memory:").unwrap();
+        for observed_at in [100, 200, 300, 400] {
+            record(&storage, observed_at, -5.0).await;
+        }
+        assert_eq!(
+            observed(&storage, "55.76,37.62", 200, 300).await,
+            vec![200, 300]
+        );
+        assert_eq!(
+            observed(&storage, "55.76,37.62", 201, 299).await,
+            Vec::<u64>::new()
+        );
+        assert_eq!(
+            observed(&storage, "elsewhere", 0, 400).await,
+            Vec::<u64>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn spend_adds_up_per_day() {
+        let storage = Storage::open(":memory:").unwrap();
+        let day = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        storage.add_spend(day, 1.5).await.unwrap();
+        storage.add_spend(day, 2.0).await.unwrap();
+        assert_eq!(storage.spend(day).await.unwrap(), 3.5);
+        assert_eq!(storage.spend(day.succ_opt().unwrap()).await.unwrap(), 0.0);
+    }
+}
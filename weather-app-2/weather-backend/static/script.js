@@ -8,20 +8,33 @@ document.getElementById("weather-form").addEventListener("submit", async (e) =>
         headers: {
             "Content-Type": "application/json",
         },
-        body: JSON.stringify({ city, timestamp: Math.floor(Date.now() / 1000) }),
+        body: JSON.stringify({ city }),
     });
 
-    const data = await response.json();
+    // every error is json with a code, but a proxy in between may answer otherwise
+    const data = await response.json().catch(() => ({}));
+    const result = document.getElementById("weather-result");
+
+    if (!response.ok) {
+        const message = data.code === "location_not_found"
+            ? `${city} not found`
+            : data.error || response.statusText;
+        result.innerHTML = `<p>${message}</p>`;
+        return;
+    }
+    const stale = response.headers.get("x-cache-status") === "stale";
+    const age = Number(response.headers.get("age") || 0);
 
     for (let [key, value] of Object.entries(data)) {
         console.log(`${key}: ${value}`);
     }
 
-    const result = document.getElementById("weather-result");
     result.innerHTML = `
+        ${data.alerts ? `<p class="alert-banner">Weather alert in effect</p>` : ""}
         <h2>${city}</h2>
         <p>Temperature: ${data.temp}°C</p>
         <p>Humidity: ${data.humidity}%</p>
-        <p>Wind Speed: ${data.wind_speed} m/s</p>
+        <p>Wind Speed: ${data.wind_speed} km/h</p>
+        ${stale ? `<p>Updated ${Math.round(age / 60)} min ago</p>` : ""}
     `;
 })